@@ -1,11 +1,11 @@
 use clap::Parser;
 use common::message::{Command, Response};
 use futures::{SinkExt, StreamExt};
-use std::{error::Error, str::FromStr};
+use std::str::FromStr;
 use tokio::io::{self, AsyncBufReadExt, BufReader};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::net::TcpStream;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use tracing::{error, info};
+use tracing::info;
 
 /// A simple caching service client that connects to a server.
 #[derive(Parser, Debug)]
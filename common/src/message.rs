@@ -1,7 +1,7 @@
 use bytes::BytesMut;
 use core::fmt;
 use rmp_serde::{from_slice, to_vec};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{Map, Value};
 use std::io::Read;
 use std::io::Write;
@@ -45,6 +45,153 @@ pub enum Command {
         body: Value,
         headers: Header,
     },
+
+    #[serde(alias = "load")]
+    LOAD { file: String, headers: Header },
+
+    #[serde(alias = "rewritelog")]
+    REWRITELOG { headers: Header },
+
+    #[serde(alias = "info")]
+    INFO { headers: Header },
+
+    #[serde(alias = "expire")]
+    EXPIRE {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    #[serde(alias = "ttl")]
+    TTL { uri: String, headers: Header },
+
+    #[serde(alias = "persist")]
+    PERSIST { uri: String, headers: Header },
+
+    #[serde(alias = "incr")]
+    INCR { uri: String, headers: Header },
+
+    #[serde(alias = "incrby")]
+    INCRBY {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    #[serde(alias = "push")]
+    PUSH {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    #[serde(alias = "pop")]
+    POP { uri: String, headers: Header },
+
+    #[serde(alias = "pull")]
+    PULL {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    #[serde(alias = "collections")]
+    COLLECTIONS { headers: Header },
+
+    #[serde(alias = "count")]
+    COUNT { uri: String, headers: Header },
+
+    #[serde(alias = "drop")]
+    DROP { uri: String, headers: Header },
+
+    #[serde(alias = "clear")]
+    CLEAR { uri: String, headers: Header },
+
+    #[serde(alias = "rename")]
+    RENAME {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    #[serde(alias = "index")]
+    INDEX {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    #[serde(alias = "dropindex")]
+    DROPINDEX {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    #[serde(alias = "indexes")]
+    INDEXES { uri: String, headers: Header },
+
+    #[serde(alias = "setschema")]
+    SETSCHEMA {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    #[serde(alias = "getschema")]
+    GETSCHEMA { uri: String, headers: Header },
+
+    #[serde(alias = "validate")]
+    VALIDATE { uri: String, headers: Header },
+
+    /// Sub-commands run in order, each with its own response; with the `atomic` header
+    /// they all take effect or none does.
+    #[serde(alias = "batch")]
+    BATCH {
+        commands: Vec<Command>,
+        headers: Header,
+    },
+
+    #[serde(alias = "mget")]
+    MGET {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    #[serde(alias = "insert")]
+    INSERT {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
+
+    /// Starts queueing this connection's commands until EXEC runs them or DISCARD drops
+    /// them.
+    #[serde(alias = "multi")]
+    MULTI { headers: Header },
+
+    #[serde(alias = "exec")]
+    EXEC { headers: Header },
+
+    #[serde(alias = "discard")]
+    DISCARD { headers: Header },
+
+    /// Makes the next EXEC on this connection abort if the object changes before it.
+    #[serde(alias = "watch")]
+    WATCH { uri: String, headers: Header },
+
+    /// Deletes the objects of a collection whose ULID keys were made before a cutoff.
+    #[serde(alias = "purge")]
+    PURGE { uri: String, headers: Header },
+
+    /// Computes a summary of the objects of a collection; the body says which.
+    #[serde(alias = "aggregate")]
+    AGGREGATE {
+        uri: String,
+        body: Value,
+        headers: Header,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -66,11 +213,31 @@ pub enum CommandParseError {
 }
 #[derive(Debug, Error)]
 #[error("failed to deserialize {0}")]
-pub struct DeserializeError(Box<dyn std::error::Error>);
+pub struct DeserializeError(Box<dyn std::error::Error + Send + Sync>);
 
 #[derive(Debug, Error)]
 #[error("failed to serialize {0}")]
-pub struct SerializeError(Box<dyn std::error::Error>);
+pub struct SerializeError(Box<dyn std::error::Error + Send + Sync>);
+
+/// Serializes `val` as msgpack and compresses it with zstd, the encoding used on the wire.
+pub fn encode<T: Serialize>(val: &T) -> Result<Vec<u8>, SerializeError> {
+    let vec = &to_vec(val).map_err(|e| SerializeError(e.into()))?;
+    let mut encoder = Encoder::new(Vec::new(), 3).map_err(|e| SerializeError(e.into()))?;
+    encoder
+        .write_all(vec)
+        .map_err(|e| SerializeError(e.into()))?;
+    encoder.finish().map_err(|e| SerializeError(e.into()))
+}
+
+/// Reverses [`encode`].
+pub fn decode<T: DeserializeOwned>(input: &[u8]) -> Result<T, DeserializeError> {
+    let mut decoder = Decoder::new(input).map_err(|e| DeserializeError(e.into()))?;
+    let mut decompressed_data = Vec::new();
+    decoder
+        .read_to_end(&mut decompressed_data)
+        .map_err(|e| DeserializeError(e.into()))?;
+    from_slice(&decompressed_data).map_err(|e| DeserializeError(e.into()))
+}
 
 impl Command {
     pub fn try_new(string: &str) -> Result<Self, CommandParseError> {
@@ -82,10 +249,24 @@ impl Command {
                     headers: Some(headers),
                 }),
                 Err(CommandParseError::NoHeader) => Ok(Command::PING { headers: None }),
-                Err(err) => return Err(err),
+                Err(err) => Err(err),
+            },
+            "info" => match Self::parse_header(tail) {
+                Ok(headers) => Ok(Command::INFO {
+                    headers: Some(headers),
+                }),
+                Err(CommandParseError::NoHeader) => Ok(Command::INFO { headers: None }),
+                Err(err) => Err(err),
+            },
+            "rewritelog" => match Self::parse_header(tail) {
+                Ok(headers) => Ok(Command::REWRITELOG {
+                    headers: Some(headers),
+                }),
+                Err(CommandParseError::NoHeader) => Ok(Command::REWRITELOG { headers: None }),
+                Err(err) => Err(err),
             },
             "get" => {
-                if uri == "" {
+                if uri.is_empty() {
                     Err(CommandParseError::MissingUri)
                 } else {
                     match Self::parse_header(tail) {
@@ -97,12 +278,12 @@ impl Command {
                             uri: uri.to_string(),
                             headers: None,
                         }),
-                        Err(err) => return Err(err),
+                        Err(err) => Err(err),
                     }
                 }
             }
             "dump" => {
-                if uri == "" {
+                if uri.is_empty() {
                     Err(CommandParseError::MissingUri)
                 } else {
                     Ok(Self::DUMP {
@@ -110,8 +291,25 @@ impl Command {
                     })
                 }
             }
+            "load" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::LOAD {
+                            file: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::LOAD {
+                            file: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
             "delete" => {
-                if uri == "" {
+                if uri.is_empty() {
                     Err(CommandParseError::MissingUri)
                 } else {
                     match Self::parse_header(tail) {
@@ -123,7 +321,7 @@ impl Command {
                             uri: uri.to_string(),
                             headers: None,
                         }),
-                        Err(err) => return Err(err),
+                        Err(err) => Err(err),
                     }
                 }
             }
@@ -141,7 +339,7 @@ impl Command {
                         body,
                         headers: None,
                     }),
-                    Err(err) => return Err(err),
+                    Err(err) => Err(err),
                 }
             }
             "put" => {
@@ -158,7 +356,7 @@ impl Command {
                         body,
                         headers: None,
                     }),
-                    Err(err) => return Err(err),
+                    Err(err) => Err(err),
                 }
             }
 
@@ -176,7 +374,442 @@ impl Command {
                         body,
                         headers: None,
                     }),
-                    Err(err) => return Err(err),
+                    Err(err) => Err(err),
+                }
+            }
+            "expire" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::EXPIRE {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::EXPIRE {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "ttl" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::TTL {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::TTL {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "persist" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::PERSIST {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::PERSIST {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "incr" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::INCR {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::INCR {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "incrby" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::INCRBY {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::INCRBY {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "push" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::PUSH {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::PUSH {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "pop" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::POP {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::POP {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "pull" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::PULL {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::PULL {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "collections" => match Self::parse_header(tail) {
+                Ok(headers) => Ok(Command::COLLECTIONS {
+                    headers: Some(headers),
+                }),
+                Err(CommandParseError::NoHeader) => Ok(Command::COLLECTIONS { headers: None }),
+                Err(err) => Err(err),
+            },
+            "count" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::COUNT {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::COUNT {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "drop" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::DROP {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::DROP {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "clear" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::CLEAR {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::CLEAR {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "rename" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::RENAME {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::RENAME {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "index" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::INDEX {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::INDEX {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "dropindex" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::DROPINDEX {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::DROPINDEX {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "indexes" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::INDEXES {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::INDEXES {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "setschema" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::SETSCHEMA {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::SETSCHEMA {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "getschema" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::GETSCHEMA {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::GETSCHEMA {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "validate" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::VALIDATE {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::VALIDATE {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "batch" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let commands = serde_json::from_value(Self::parse_body(head)?)
+                    .map_err(CommandParseError::BodyParseFailed)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::BATCH {
+                        commands,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::BATCH {
+                        commands,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "mget" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::MGET {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::MGET {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "insert" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::INSERT {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::INSERT {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
+                }
+            }
+            "multi" => match Self::parse_header(tail) {
+                Ok(headers) => Ok(Command::MULTI {
+                    headers: Some(headers),
+                }),
+                Err(CommandParseError::NoHeader) => Ok(Command::MULTI { headers: None }),
+                Err(err) => Err(err),
+            },
+            "exec" => match Self::parse_header(tail) {
+                Ok(headers) => Ok(Command::EXEC {
+                    headers: Some(headers),
+                }),
+                Err(CommandParseError::NoHeader) => Ok(Command::EXEC { headers: None }),
+                Err(err) => Err(err),
+            },
+            "discard" => match Self::parse_header(tail) {
+                Ok(headers) => Ok(Command::DISCARD {
+                    headers: Some(headers),
+                }),
+                Err(CommandParseError::NoHeader) => Ok(Command::DISCARD { headers: None }),
+                Err(err) => Err(err),
+            },
+            "watch" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::WATCH {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::WATCH {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "purge" => {
+                if uri.is_empty() {
+                    Err(CommandParseError::MissingUri)
+                } else {
+                    match Self::parse_header(tail) {
+                        Ok(headers) => Ok(Command::PURGE {
+                            uri: uri.to_string(),
+                            headers: Some(headers),
+                        }),
+                        Err(CommandParseError::NoHeader) => Ok(Command::PURGE {
+                            uri: uri.to_string(),
+                            headers: None,
+                        }),
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            "aggregate" => {
+                let (head, tail) = tail.split_once('\n').unwrap_or((tail, ""));
+                let body = Self::parse_body(head)?;
+                match Self::parse_header(tail) {
+                    Ok(headers) => Ok(Command::AGGREGATE {
+                        uri: uri.to_string(),
+                        body,
+                        headers: Some(headers),
+                    }),
+                    Err(CommandParseError::NoHeader) => Ok(Command::AGGREGATE {
+                        uri: uri.to_string(),
+                        body,
+                        headers: None,
+                    }),
+                    Err(err) => Err(err),
                 }
             }
             _ => Err(CommandParseError::NoCommandFound),
@@ -205,21 +838,11 @@ impl Command {
     }
 
     pub fn from_slice(input: &BytesMut) -> Result<Self, DeserializeError> {
-        let mut decoder = Decoder::new(&input[..]).map_err(|e| DeserializeError(e.into()))?;
-        let mut decompressed_data = Vec::new();
-        decoder
-            .read_to_end(&mut decompressed_data)
-            .map_err(|e| DeserializeError(e.into()))?;
-        from_slice(&decompressed_data).map_err(|e| DeserializeError(e.into()))
+        decode(input)
     }
 
     pub fn to_vec(val: &Self) -> Result<Vec<u8>, SerializeError> {
-        let vec = &to_vec(val).map_err(|e| SerializeError(e.into()))?;
-        let mut encoder = Encoder::new(Vec::new(), 3).map_err(|e| SerializeError(e.into()))?;
-        encoder
-            .write_all(vec)
-            .map_err(|e| SerializeError(e.into()))?;
-        encoder.finish().map_err(|e| SerializeError(e.into()))
+        encode(val)
     }
 }
 
@@ -253,45 +876,57 @@ pub enum Response {
 
     #[serde(alias = "ok")]
     OK,
+
+    /// Part of a collection; `cursor` fetches the next part and is `None` after the last.
+    #[serde(alias = "page")]
+    PAGE {
+        items: Vec<Value>,
+        cursor: Option<String>,
+    },
+
+    /// One response per command of a batch, in order.
+    #[serde(alias = "batch")]
+    BATCH(Vec<Response>),
+
+    /// The command will run at EXEC.
+    #[serde(alias = "queued")]
+    QUEUED,
 }
 
 impl Response {
     pub fn from_slice(input: &BytesMut) -> Result<Self, DeserializeError> {
-        let mut decoder = Decoder::new(&input[..]).map_err(|e| DeserializeError(e.into()))?;
-        let mut decompressed_data = Vec::new();
-        decoder
-            .read_to_end(&mut decompressed_data)
-            .map_err(|e| DeserializeError(e.into()))?;
-        from_slice(&decompressed_data).map_err(|e| DeserializeError(e.into()))
+        decode(input)
     }
 
     pub fn to_vec(val: &Self) -> Result<Vec<u8>, SerializeError> {
-        let vec = &to_vec(val).map_err(|e| SerializeError(e.into()))?;
-        let mut encoder = Encoder::new(Vec::new(), 3).map_err(|e| SerializeError(e.into()))?;
-        encoder
-            .write_all(vec)
-            .map_err(|e| SerializeError(e.into()))?;
-        encoder.finish().map_err(|e| SerializeError(e.into()))
+        encode(val)
     }
 }
 
 impl fmt::Display for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Response::NULL => write!(f, "{}", "null"),
-            Response::PONG => write!(f, "{}", "pong"),
-            Response::OK => write!(f, "{}", "ok"),
+            Response::NULL => write!(f, "null"),
+            Response::PONG => write!(f, "pong"),
+            Response::OK => write!(f, "ok"),
+            Response::QUEUED => write!(f, "queued"),
             Response::OBJECT(value) => write!(f, "{}", print_value(value)),
-            Response::COLLECTION(values) => {
-                let mut res = "[".to_string();
-                for (i, v) in values.iter().enumerate() {
-                    res.push_str(&print_value(v));
-                    if i < values.len() - 1 {
-                        res.push_str(", ");
+            Response::COLLECTION(values) => write!(f, "{}", print_values(values)),
+            Response::BATCH(responses) => {
+                for (i, response) in responses.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
                     }
+                    write!(f, "{}) {}", i + 1, response)?;
+                }
+                Ok(())
+            }
+            Response::PAGE { items, cursor } => {
+                write!(f, "{}", print_values(items))?;
+                match cursor {
+                    Some(cursor) => write!(f, "\ncursor: {}", cursor),
+                    None => write!(f, "\ncursor: none"),
                 }
-                res.push_str("]");
-                write!(f, "{}", res)
             }
             _ => {
                 write!(f, "{}", serde_json::to_string(self).unwrap())
@@ -300,23 +935,25 @@ impl fmt::Display for Response {
     }
 }
 
+fn print_values(values: &[Value]) -> String {
+    let mut res = "[".to_string();
+    for (i, v) in values.iter().enumerate() {
+        res.push_str(&print_value(v));
+        if i < values.len() - 1 {
+            res.push_str(", ");
+        }
+    }
+    res.push(']');
+    res
+}
+
 fn print_value(v: &Value) -> String {
     match v {
         Value::Null => "null".to_string(),
         Value::Bool(b) => b.to_string(),
         Value::Number(n) => n.to_string(),
         Value::String(s) => format!("\"{}\"", s.clone()),
-        Value::Array(a) => {
-            let mut res = "[".to_string();
-            for (i, v) in a.iter().enumerate() {
-                res.push_str(&print_value(v));
-                if i < a.len() - 1 {
-                    res.push_str(", ");
-                }
-            }
-            res.push_str("]");
-            res
-        }
+        Value::Array(a) => print_values(a),
         Value::Object(o) => {
             let mut res = "{".to_string();
             for (i, (k, v)) in o.iter().enumerate() {
@@ -325,7 +962,7 @@ fn print_value(v: &Value) -> String {
                     res.push_str(", ");
                 }
             }
-            res.push_str("}");
+            res.push('}');
             res
         }
     }
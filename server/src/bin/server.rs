@@ -1,9 +1,14 @@
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use clap::Parser;
 use tokio::net::TcpListener;
-use tracing::{error, info};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
 // import handle_connection from lib
 
+/// Refused clients told why at the same time; any more are disconnected without a word.
+const MAX_REJECTIONS: usize = 64;
 
 /// A simple caching service server that listens on a port.
 #[derive(Parser, Debug)]
@@ -12,6 +17,39 @@ struct ServerArgs {
     /// Port to listen on.
     #[clap(short, long, default_value = "6379")]
     port: u16,
+
+    /// Maximum number of clients served at the same time.
+    #[clap(long, default_value = "1024")]
+    max_connections: usize,
+
+    /// Memory limit for stored objects, in bytes or with a kb/mb/gb suffix; 0 means none.
+    #[clap(long, default_value = "0", value_parser = parse_size)]
+    max_memory: usize,
+
+    /// What to do when a write would go over --max-memory.
+    #[clap(long, value_enum, default_value = "noeviction")]
+    eviction_policy: lib::EvictionPolicy,
+
+    /// Snapshot file to fill the keyspace from before accepting clients.
+    #[clap(long)]
+    snapshot: Option<PathBuf>,
+
+    /// Append-only log of every mutation, replayed on top of the snapshot at startup.
+    #[clap(long)]
+    appendonly: Option<PathBuf>,
+
+    /// When the append-only log is forced to disk.
+    #[clap(long, value_enum, default_value = "everysec")]
+    appendfsync: lib::FsyncPolicy,
+
+    /// Rewrite the append-only log once it has grown by this many percent since the last
+    /// rewrite; 0 disables automatic rewrites.
+    #[clap(long, default_value = "100")]
+    auto_rewrite_percentage: u64,
+
+    /// Smallest append-only log size, in bytes, that is rewritten automatically.
+    #[clap(long, default_value = "67108864")]
+    auto_rewrite_min_size: u64,
 }
 
 #[tokio::main]
@@ -23,19 +61,100 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = ServerArgs::parse();
     let addr = format!("0.0.0.0:{}", args.port);
 
+    // One keyspace for the whole process, shared by every connection.
+    let kv = Arc::new(lib::Keyspace::with_memory(lib::MemoryConfig {
+        max_memory: args.max_memory,
+        policy: args.eviction_policy,
+    }));
+    if let Some(path) = &args.snapshot {
+        let report = lib::Snapshot::read(path)
+            .and_then(|snapshot| kv.load(snapshot, lib::LoadMode::Replace))
+            .map_err(|e| format!("failed to load snapshot {}: {}", path.display(), e))?;
+        info!(
+            "Loaded {} objects in {} collections from {}",
+            report.objects,
+            report.collections,
+            path.display()
+        );
+    }
+
+    if let Some(path) = &args.appendonly {
+        let report = kv
+            .open_log(
+                path,
+                lib::LogConfig {
+                    fsync: args.appendfsync,
+                    auto_rewrite_percentage: args.auto_rewrite_percentage,
+                    auto_rewrite_min_size: args.auto_rewrite_min_size,
+                },
+            )
+            .map_err(|e| format!("failed to replay {}: {}", path.display(), e))?;
+        info!(
+            "Replayed {} records from {}",
+            report.records,
+            path.display()
+        );
+    }
+    tokio::spawn(lib::run_background_tasks(kv.clone()));
+
     // Bind a TCP listener to the specified address.
     let listener = TcpListener::bind(&addr).await?;
     info!("Server listening on {}", addr);
+    let connections = Arc::new(Semaphore::new(args.max_connections));
+    let rejections = Arc::new(Semaphore::new(MAX_REJECTIONS));
 
     // Accept incoming connections in a loop.
     loop {
         match listener.accept().await {
             Ok((socket, peer_addr)) => {
-                info!("Accepted connection from {}", peer_addr);
-                lib::handle_connection(socket).await?;
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    warn!("Rejecting {}: max connections reached", peer_addr);
+                    // Dropping the socket closes it, so a flood of clients can't pile up
+                    // tasks and file descriptors here either.
+                    let Ok(rejecting) = rejections.clone().try_acquire_owned() else {
+                        continue;
+                    };
+                    tokio::spawn(async move {
+                        if let Err(e) =
+                            lib::reject_connection(socket, "max number of clients reached").await
+                        {
+                            error!("Failed to reject {}: {}", peer_addr, e);
+                        }
+                        drop(rejecting);
+                    });
+                    continue;
+                };
+                let kv = kv.clone();
+                // Each client gets its own task; a failure here only ends that connection.
+                tokio::spawn(async move {
+                    if let Err(e) = lib::handle_connection(socket, kv).await {
+                        error!("Connection with {} failed: {}", peer_addr, e);
+                    }
+                    drop(permit);
+                });
             }
             Err(e) => error!("Failed to accept connection: {}", e),
         }
     }
 }
 
+/// Parses a byte count such as `1048576`, `512kb`, `100mb` or `2gb`.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim().to_lowercase();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s.as_str(), ""),
+    };
+    let multiplier = match unit.trim() {
+        "" | "b" => 1,
+        "kb" | "k" => 1 << 10,
+        "mb" | "m" => 1 << 20,
+        "gb" | "g" => 1 << 30,
+        other => return Err(format!("unknown size unit {:?}", other)),
+    };
+    digits
+        .parse::<usize>()
+        .map_err(|e| e.to_string())?
+        .checked_mul(multiplier)
+        .ok_or_else(|| "size is too large".to_string())
+}
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+
+use serde_json::{Map, Number, Value};
+
+use crate::pointer::Pointer;
+use crate::query::{field_path, order, QueryError};
+
+/// A summary of the objects of a collection, read from the body of AGGREGATE:
+/// `{"field": path, "group_by": path or [paths], "ops": [names]}`.
+///
+/// Every part is optional. Without `ops` all of them are computed, or only `count` when
+/// there is no `field`. Objects are fed in with [`Aggregation::add`] and the result is
+/// taken with [`Aggregation::finish`].
+#[derive(Debug)]
+pub struct Aggregation {
+    field: Option<Pointer>,
+    /// The paths as the client wrote them, which name the group fields in the result.
+    group_by: Vec<(String, Pointer)>,
+    ops: Vec<Op>,
+    groups: HashMap<Vec<String>, Group>,
+}
+
+/// What can be computed over a field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    /// Objects in the group, whether or not they hold the field.
+    Count,
+    /// The numbers at the field, in integers while every one is an integer.
+    Sum,
+    /// The lowest value at the field, in the order reads sort in.
+    Min,
+    Max,
+    /// The mean of the numbers at the field.
+    Avg,
+    /// Different values at the field.
+    Distinct,
+}
+
+impl Op {
+    const ALL: [Op; 6] = [Op::Count, Op::Sum, Op::Min, Op::Max, Op::Avg, Op::Distinct];
+
+    fn parse(name: &str) -> Option<Self> {
+        Op::ALL
+            .into_iter()
+            .find(|op| op.name().eq_ignore_ascii_case(name))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Count => "count",
+            Op::Sum => "sum",
+            Op::Min => "min",
+            Op::Max => "max",
+            Op::Avg => "avg",
+            Op::Distinct => "distinct",
+        }
+    }
+}
+
+/// The objects sharing the same values at the group paths, and what was gathered about
+/// their field. `null` and missing fields only count towards `count`.
+#[derive(Debug, Default)]
+struct Group {
+    key: Vec<Value>,
+    count: u64,
+    sum: Sum,
+    /// Numbers seen, for the mean.
+    numbers: u64,
+    min: Option<Value>,
+    max: Option<Value>,
+    distinct: HashSet<String>,
+}
+
+#[derive(Debug)]
+enum Sum {
+    Int(i64),
+    Float(f64),
+}
+
+impl Default for Sum {
+    fn default() -> Self {
+        Sum::Int(0)
+    }
+}
+
+impl Sum {
+    fn add(&mut self, n: &Number) {
+        *self = match (&*self, n.as_i64()) {
+            (Sum::Int(sum), Some(n)) if sum.checked_add(n).is_some() => Sum::Int(sum + n),
+            _ => Sum::Float(self.as_f64() + n.as_f64().unwrap_or_default()),
+        };
+    }
+
+    fn as_f64(&self) -> f64 {
+        match self {
+            Sum::Int(sum) => *sum as f64,
+            Sum::Float(sum) => *sum,
+        }
+    }
+
+    fn value(&self) -> Value {
+        match self {
+            Sum::Int(sum) => Value::from(*sum),
+            Sum::Float(sum) => Number::from_f64(*sum).map_or(Value::Null, Value::Number),
+        }
+    }
+}
+
+impl Aggregation {
+    pub fn from_body(body: &Value) -> Result<Self, QueryError> {
+        let root = Pointer::root();
+        let body = match body {
+            Value::Null => &Map::new(),
+            Value::Object(body) => body,
+            _ => return Err(QueryError::new(&root, "expected an object")),
+        };
+        let path = |at: &Pointer, path: &Value| match path {
+            Value::String(path) => field_path(path)
+                .map(|pointer| (path.clone(), pointer))
+                .map_err(|reason| QueryError::new(at, reason)),
+            _ => Err(QueryError::new(at, "expected a field path")),
+        };
+        let field = match body.get("field") {
+            None | Some(Value::Null) => None,
+            Some(field) => Some(path(&root.join("field"), field)?.1),
+        };
+        let at = root.join("group_by");
+        let group_by = match body.get("group_by") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(paths)) => paths
+                .iter()
+                .enumerate()
+                .map(|(i, p)| path(&at.join(i.to_string()), p))
+                .collect::<Result<_, _>>()?,
+            Some(p) => vec![path(&at, p)?],
+        };
+        let at = root.join("ops");
+        let ops = match body.get("ops") {
+            None | Some(Value::Null) if field.is_some() => Op::ALL.to_vec(),
+            None | Some(Value::Null) => vec![Op::Count],
+            Some(Value::Array(names)) if !names.is_empty() => names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| {
+                    let at = at.join(i.to_string());
+                    match name.as_str().and_then(Op::parse) {
+                        Some(Op::Count) => Ok(Op::Count),
+                        Some(_) if field.is_none() => {
+                            Err(QueryError::new(&at, "only count works without a field"))
+                        }
+                        Some(op) => Ok(op),
+                        None => Err(QueryError::new(&at, "unknown operation")),
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(QueryError::new(&at, "expected an array of operations")),
+        };
+        Ok(Self {
+            field,
+            group_by,
+            ops,
+            groups: HashMap::new(),
+        })
+    }
+
+    /// Takes one object into the summary.
+    pub fn add(&mut self, value: &Value) {
+        let key: Vec<_> = self
+            .group_by
+            .iter()
+            .map(|(_, path)| path.get(value).cloned().unwrap_or(Value::Null))
+            .collect();
+        let group = self
+            .groups
+            .entry(key.iter().map(distinct_key).collect())
+            .or_insert_with(|| Group {
+                key,
+                ..Group::default()
+            });
+        group.count += 1;
+        let Some(field) = self.field.as_ref().and_then(|field| field.get(value)) else {
+            return;
+        };
+        if field.is_null() {
+            return;
+        }
+        if let Value::Number(n) = field {
+            group.sum.add(n);
+            group.numbers += 1;
+        }
+        if group
+            .min
+            .as_ref()
+            .is_none_or(|min| order(Some(field), Some(min)).is_lt())
+        {
+            group.min = Some(field.clone());
+        }
+        if group
+            .max
+            .as_ref()
+            .is_none_or(|max| order(Some(field), Some(max)).is_gt())
+        {
+            group.max = Some(field.clone());
+        }
+        if self.ops.contains(&Op::Distinct) {
+            group.distinct.insert(distinct_key(field));
+        }
+    }
+
+    /// The summary: one object of results when nothing is grouped, or else an array of
+    /// them, each with a `group` object holding the values it was grouped by, sorted by
+    /// those values.
+    pub fn finish(mut self) -> Value {
+        if self.group_by.is_empty() {
+            let group = self.groups.remove(&Vec::new()).unwrap_or_default();
+            return Value::Object(self.results(group));
+        }
+        let mut groups: Vec<_> = std::mem::take(&mut self.groups).into_values().collect();
+        groups.sort_by(|a, b| {
+            a.key
+                .iter()
+                .zip(&b.key)
+                .map(|(a, b)| order(Some(a), Some(b)))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        groups
+            .into_iter()
+            .map(|mut group| {
+                let key = std::mem::take(&mut group.key);
+                let mut results = Map::new();
+                results.insert(
+                    "group".to_string(),
+                    Value::Object(
+                        self.group_by
+                            .iter()
+                            .map(|(path, _)| path.clone())
+                            .zip(key)
+                            .collect(),
+                    ),
+                );
+                results.extend(self.results(group));
+                Value::Object(results)
+            })
+            .collect()
+    }
+
+    fn results(&self, group: Group) -> Map<String, Value> {
+        let avg = match group.numbers {
+            0 => Value::Null,
+            n => Number::from_f64(group.sum.as_f64() / n as f64).map_or(Value::Null, Value::Number),
+        };
+        self.ops
+            .iter()
+            .map(|op| {
+                let result = match op {
+                    Op::Count => Value::from(group.count),
+                    Op::Sum => group.sum.value(),
+                    Op::Min => group.min.clone().unwrap_or(Value::Null),
+                    Op::Max => group.max.clone().unwrap_or(Value::Null),
+                    Op::Avg => avg.clone(),
+                    Op::Distinct => Value::from(group.distinct.len()),
+                };
+                (op.name().to_string(), result)
+            })
+            .collect()
+    }
+}
+
+/// A string equal for equal values, with `1` and `1.0` taken as the same number.
+fn distinct_key(value: &Value) -> String {
+    match value {
+        Value::Number(n) if n.as_i64().is_none() && n.as_u64().is_none() => {
+            let f = n.as_f64().unwrap_or_default();
+            if f.fract() == 0.0 && f.abs() < i64::MAX as f64 {
+                (f as i64).to_string()
+            } else {
+                f.to_string()
+            }
+        }
+        value => value.to_string(),
+    }
+}
@@ -0,0 +1,705 @@
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::index::IndexSpec;
+use crate::snapshot::Snapshot;
+
+/// Length and CRC32 of the payload that follows.
+const RECORD_HEADER_LEN: usize = 8;
+
+/// How far past a record whose length runs off the end replay looks for a good record,
+/// which tells damage in the middle of the log from a write torn at its end.
+const RESYNC_WINDOW: usize = 1 << 20;
+
+/// When appended records are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FsyncPolicy {
+    /// After every record; nothing acknowledged is ever lost.
+    Always,
+    /// Once a second from the background task; at most a second of writes is lost.
+    Everysec,
+    /// Never explicitly; the operating system decides.
+    No,
+}
+
+/// One applied mutation.
+///
+/// Records hold the outcome of a command rather than the command itself, so replay never
+/// has to regenerate ids or re-run merges and ends up with exactly what clients were told.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LogRecord<'a> {
+    Set {
+        collection: Cow<'a, str>,
+        id: Cow<'a, str>,
+        value: Cow<'a, Value>,
+        /// Unix time in milliseconds at which the object expires.
+        #[serde(default)]
+        expires_at: Option<u64>,
+        /// Version the write gave the object; 0 in logs written before objects had one.
+        #[serde(default)]
+        version: u64,
+    },
+    Delete {
+        collection: Cow<'a, str>,
+        id: Cow<'a, str>,
+    },
+    /// An object's expiry time changed; `None` keeps it forever.
+    Expire {
+        collection: Cow<'a, str>,
+        id: Cow<'a, str>,
+        expires_at: Option<u64>,
+    },
+    /// Everything before this record was dropped, as done by a replacing LOAD.
+    Flush,
+    /// A collection and all of its objects were removed.
+    DropCollection { collection: Cow<'a, str> },
+    /// Every object of a collection was removed; the collection itself stays.
+    ClearCollection { collection: Cow<'a, str> },
+    /// A collection took a new name that no other collection had.
+    RenameCollection {
+        from: Cow<'a, str>,
+        to: Cow<'a, str>,
+    },
+    /// An index was declared on a collection, creating the collection if needed.
+    CreateIndex {
+        collection: Cow<'a, str>,
+        spec: Cow<'a, IndexSpec>,
+    },
+    /// A collection's schema was set, creating the collection if needed, or removed.
+    SetSchema {
+        collection: Cow<'a, str>,
+        schema: Option<Cow<'a, Value>>,
+    },
+    /// The index on `path` was removed from a collection.
+    DropIndex {
+        collection: Cow<'a, str>,
+        path: Cow<'a, str>,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum AofError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("failed to encode log record: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+
+    #[error("invalid record at offset {offset}: {reason}")]
+    InvalidRecord { offset: u64, reason: String },
+
+    #[error("a rewrite is already in progress")]
+    RewriteInProgress,
+
+    #[error("append-only log is not enabled")]
+    Disabled,
+}
+
+/// What replaying a log found.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReplayReport {
+    pub records: usize,
+    /// Bytes of an incomplete trailing record that were cut off.
+    pub truncated: u64,
+}
+
+/// How the append-only log is written and when it is compacted.
+#[derive(Debug, Clone, Copy)]
+pub struct LogConfig {
+    pub fsync: FsyncPolicy,
+    /// Rewrite once the log has grown by this many percent since the last rewrite; 0
+    /// turns automatic rewrites off.
+    pub auto_rewrite_percentage: u64,
+    /// Logs smaller than this are never rewritten automatically.
+    pub auto_rewrite_min_size: u64,
+}
+
+/// The append-only log of every mutation applied to the keyspace.
+///
+/// Each record is framed as a little-endian `u32` length, a CRC32 of the payload and the
+/// msgpack-encoded [`LogRecord`].
+pub struct Aof {
+    path: PathBuf,
+    config: LogConfig,
+    file: Mutex<LogFile>,
+    rewrite: Mutex<RewriteState>,
+    /// Objects written to the new log by the rewrite in progress.
+    rewritten: AtomicU64,
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    /// Size right after startup or the last rewrite, the baseline for automatic rewrites.
+    base_size: u64,
+    /// Records appended while a rewrite runs; they are added to the new log before it
+    /// replaces the old one.
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct RewriteState {
+    in_progress: bool,
+    started_at: Option<SystemTime>,
+    objects: u64,
+    last: Option<RewriteOutcome>,
+}
+
+struct RewriteOutcome {
+    finished_at: SystemTime,
+    duration: Duration,
+    result: Result<u64, String>,
+}
+
+impl Aof {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &Path, config: LogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            config,
+            file: Mutex::new(LogFile {
+                file,
+                size,
+                base_size: size,
+                rewrite_buffer: None,
+            }),
+            rewrite: Mutex::default(),
+            rewritten: AtomicU64::new(0),
+        })
+    }
+
+    pub fn policy(&self) -> FsyncPolicy {
+        self.config.fsync
+    }
+
+    /// Appends one record, syncing it right away under [`FsyncPolicy::Always`].
+    pub fn append(&self, record: &LogRecord) -> Result<(), AofError> {
+        self.append_all(std::slice::from_ref(record))
+    }
+
+    /// Appends `records` in a single write. If the write fails, the log is cut back to
+    /// where it was, so it holds all of them or none.
+    pub fn append_all(&self, records: &[LogRecord]) -> Result<(), AofError> {
+        let mut frames = Vec::new();
+        for record in records {
+            frames.extend(encode_record(record)?);
+        }
+        let mut log = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let written = log.file.write_all(&frames).and_then(|()| {
+            if self.config.fsync == FsyncPolicy::Always {
+                log.file.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = written {
+            let size = log.size;
+            let undone = log
+                .file
+                .set_len(size)
+                .and_then(|()| log.file.seek(SeekFrom::End(0)));
+            if let Err(undo) = undone {
+                warn!(
+                    "Failed to cut a partly written record off {}: {}",
+                    self.path.display(),
+                    undo
+                );
+            }
+            return Err(e.into());
+        }
+        log.size += frames.len() as u64;
+        if let Some(buffer) = &mut log.rewrite_buffer {
+            buffer.extend_from_slice(&frames);
+        }
+        Ok(())
+    }
+
+    /// Forces everything appended so far to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        // Sync through a duplicate handle so appends aren't held up by the fsync.
+        let file = self
+            .file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .file
+            .try_clone()?;
+        file.sync_data()
+    }
+
+    /// Whether the log has grown enough since the last rewrite to be rewritten again.
+    pub fn needs_rewrite(&self) -> bool {
+        if self.config.auto_rewrite_percentage == 0 {
+            return false;
+        }
+        let log = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        log.rewrite_buffer.is_none()
+            && log.size >= self.config.auto_rewrite_min_size
+            && log.size >= log.base_size * (100 + self.config.auto_rewrite_percentage) / 100
+    }
+
+    /// Starts collecting appended records for a rewrite of `objects` objects.
+    ///
+    /// The caller must make sure no mutation happens between this call and taking the
+    /// snapshot the rewrite is built from.
+    pub fn begin_rewrite(&self, objects: u64) -> Result<(), AofError> {
+        let mut state = self.rewrite.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.in_progress {
+            return Err(AofError::RewriteInProgress);
+        }
+        state.in_progress = true;
+        state.started_at = Some(SystemTime::now());
+        state.objects = objects;
+        self.rewritten.store(0, Ordering::Relaxed);
+        self.file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .rewrite_buffer = Some(Vec::new());
+        Ok(())
+    }
+
+    /// Writes a minimal log holding exactly `snapshot`, then swaps it in for the current
+    /// log together with everything appended since [`Aof::begin_rewrite`].
+    pub fn rewrite(&self, snapshot: &Snapshot) -> Result<u64, AofError> {
+        let started = Instant::now();
+        let tmp = self.rewrite_path();
+        let result = self.write_rewrite(snapshot, &tmp);
+        if result.is_err() {
+            self.file
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .rewrite_buffer = None;
+            if let Err(e) = fs::remove_file(&tmp) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to remove {}: {}", tmp.display(), e);
+                }
+            }
+        }
+        let mut state = self.rewrite.lock().unwrap_or_else(PoisonError::into_inner);
+        state.in_progress = false;
+        state.last = Some(RewriteOutcome {
+            finished_at: SystemTime::now(),
+            duration: started.elapsed(),
+            result: result.as_ref().map(|size| *size).map_err(|e| e.to_string()),
+        });
+        result
+    }
+
+    /// Where a rewrite builds the new log before it replaces the current one.
+    fn rewrite_path(&self) -> PathBuf {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".rewrite");
+        PathBuf::from(tmp)
+    }
+
+    fn write_rewrite(&self, snapshot: &Snapshot, tmp: &Path) -> Result<u64, AofError> {
+        let mut file = BufWriter::new(File::create(tmp)?);
+        // The new log stands on its own, so replaying it must not keep anything a
+        // snapshot loaded before it may hold.
+        file.write_all(&encode_record(&LogRecord::Flush)?)?;
+        for collection in &snapshot.collections {
+            for object in &collection.objects {
+                file.write_all(&encode_record(&LogRecord::Set {
+                    collection: Cow::Borrowed(&collection.name),
+                    id: Cow::Borrowed(&object.id),
+                    value: Cow::Borrowed(&object.value),
+                    expires_at: object.expires_at,
+                    version: object.version,
+                })?)?;
+                self.rewritten.fetch_add(1, Ordering::Relaxed);
+            }
+            for spec in &collection.indexes {
+                file.write_all(&encode_record(&LogRecord::CreateIndex {
+                    collection: Cow::Borrowed(&collection.name),
+                    spec: Cow::Borrowed(spec),
+                })?)?;
+            }
+            if let Some(schema) = &collection.schema {
+                file.write_all(&encode_record(&LogRecord::SetSchema {
+                    collection: Cow::Borrowed(&collection.name),
+                    schema: Some(Cow::Borrowed(schema)),
+                })?)?;
+            }
+        }
+        let mut file = file.into_inner().map_err(|e| e.into_error())?;
+        // Synced before appends are held up, so they only wait for what was buffered.
+        file.sync_all()?;
+
+        // Appends wait from here until the new log is in place, so none can be lost
+        // between draining the buffer and switching files.
+        let mut log = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(buffer) = log.rewrite_buffer.as_ref().filter(|b| !b.is_empty()) {
+            file.write_all(buffer)?;
+            file.sync_data()?;
+        }
+        let size = file.metadata()?.len();
+        fs::rename(tmp, &self.path)?;
+        // Once renamed, the new file is the log, so appends must go to it even if syncing
+        // the directory fails. Its handle already sits at the end, where they belong.
+        log.file = file;
+        log.size = size;
+        log.base_size = size;
+        log.rewrite_buffer = None;
+        if let Some(dir) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+                warn!(
+                    "Failed to sync {} after the log rewrite: {}",
+                    dir.display(),
+                    e
+                );
+            }
+        }
+        Ok(size)
+    }
+
+    /// Where the log and its rewrites stand, for the INFO command.
+    pub fn info(&self) -> Value {
+        let (size, base_size, buffered) = {
+            let log = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+            let buffered = log.rewrite_buffer.as_ref().map_or(0, Vec::len);
+            (log.size, log.base_size, buffered)
+        };
+        let state = self.rewrite.lock().unwrap_or_else(PoisonError::into_inner);
+        let last = state.last.as_ref().map(|outcome| {
+            let mut last = json!({
+                "finished_at": unix_millis(outcome.finished_at),
+                "duration_ms": outcome.duration.as_millis() as u64,
+            });
+            match &outcome.result {
+                Ok(size) => {
+                    last["status"] = json!("ok");
+                    last["size"] = json!(size);
+                }
+                Err(e) => {
+                    last["status"] = json!("error");
+                    last["error"] = json!(e);
+                }
+            }
+            last
+        });
+        json!({
+            "path": self.path.display().to_string(),
+            "fsync": self.config.fsync.to_possible_value().map(|v| v.get_name().to_string()),
+            "size": size,
+            "base_size": base_size,
+            "rewrite": {
+                "in_progress": state.in_progress,
+                "started_at": state.started_at.filter(|_| state.in_progress).map(unix_millis),
+                "objects_written": self.rewritten.load(Ordering::Relaxed),
+                "objects_total": state.objects,
+                "buffered_bytes": buffered,
+                "last": last,
+            },
+        })
+    }
+
+    /// Feeds every record in the log at `path` to `apply`, in order.
+    ///
+    /// A missing file is an empty log. A torn record at the very end, as left by a crash in
+    /// the middle of a write, is cut off so new records can be appended after the last good
+    /// one; damage anywhere else is reported as an error.
+    pub fn replay(
+        path: &Path,
+        mut apply: impl FnMut(LogRecord<'static>),
+    ) -> Result<ReplayReport, AofError> {
+        let mut bytes = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ReplayReport::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut report = ReplayReport::default();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let rest = &bytes[offset..];
+            if rest.len() < RECORD_HEADER_LEN {
+                break;
+            }
+            let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+            let invalid = |reason: String| AofError::InvalidRecord {
+                offset: offset as u64,
+                reason,
+            };
+            let Some(payload) = rest.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len) else {
+                // A torn write leaves nothing after it; a damaged length in the middle of
+                // the log still has good records behind it.
+                if record_follows(&rest[RECORD_HEADER_LEN..]) {
+                    return Err(invalid(
+                        "the record runs past the end of the log".to_string(),
+                    ));
+                }
+                break;
+            };
+            if crc32fast::hash(payload) != checksum {
+                if offset + RECORD_HEADER_LEN + len == bytes.len() {
+                    break;
+                }
+                return Err(invalid("checksum mismatch".to_string()));
+            }
+            let record = rmp_serde::from_slice(payload).map_err(|e| invalid(e.to_string()))?;
+            apply(record);
+            report.records += 1;
+            offset += RECORD_HEADER_LEN + len;
+        }
+
+        if offset < bytes.len() {
+            report.truncated = (bytes.len() - offset) as u64;
+            warn!(
+                "Discarding {} bytes of an incomplete record at the end of {}",
+                report.truncated,
+                path.display()
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(offset as u64)?;
+        }
+        Ok(report)
+    }
+}
+
+/// Whether a whole record with a matching checksum starts within the first
+/// [`RESYNC_WINDOW`] bytes of `bytes`.
+///
+/// Each candidate is decoded before its checksum is taken, since damaged bytes almost
+/// never get far as a record, so a large damaged tail doesn't stall replay.
+fn record_follows(bytes: &[u8]) -> bool {
+    (0..bytes.len().min(RESYNC_WINDOW)).any(|start| {
+        let rest = &bytes[start..];
+        let Some(header) = rest.get(..RECORD_HEADER_LEN) else {
+            return false;
+        };
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        rest.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN.saturating_add(len))
+            .is_some_and(|payload| {
+                rmp_serde::from_slice::<LogRecord>(payload).is_ok()
+                    && crc32fast::hash(payload) == checksum
+            })
+    })
+}
+
+fn encode_record(record: &LogRecord) -> Result<Vec<u8>, AofError> {
+    let payload = rmp_serde::to_vec(record)?;
+    let mut frame = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::{CollectionDump, ObjectDump};
+
+    /// A log path of its own for each test, removed when dropped.
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("rayo-aof-{}-{}.aof", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+
+        fn open(&self) -> Aof {
+            let config = LogConfig {
+                fsync: FsyncPolicy::No,
+                auto_rewrite_percentage: 0,
+                auto_rewrite_min_size: 0,
+            };
+            Aof::open(&self.0, config).unwrap()
+        }
+
+        /// The ids of the Set records, or the replay error.
+        fn replay(&self) -> Result<(Vec<String>, ReplayReport), AofError> {
+            let mut ids = Vec::new();
+            let report = Aof::replay(&self.0, |record| {
+                if let LogRecord::Set { id, .. } = record {
+                    ids.push(id.into_owned());
+                }
+            })?;
+            Ok((ids, report))
+        }
+
+        fn len(&self) -> u64 {
+            fs::metadata(&self.0).unwrap().len()
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn set(id: &str) -> LogRecord<'_> {
+        LogRecord::Set {
+            collection: Cow::Borrowed("c"),
+            id: Cow::Borrowed(id),
+            value: Cow::Owned(json!({"id": id})),
+            expires_at: None,
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn a_missing_log_is_empty() {
+        let log = TempLog::new("missing");
+        let (ids, report) = log.replay().unwrap();
+        assert!(ids.is_empty());
+        assert_eq!(report.records, 0);
+    }
+
+    #[test]
+    fn records_replay_in_order() {
+        let log = TempLog::new("order");
+        let aof = log.open();
+        aof.append(&set("a")).unwrap();
+        aof.append_all(&[set("b"), LogRecord::Flush, set("c")])
+            .unwrap();
+        let (ids, report) = log.replay().unwrap();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert_eq!(report.records, 4);
+        assert_eq!(report.truncated, 0);
+    }
+
+    #[test]
+    fn a_torn_tail_is_cut_off() {
+        let log = TempLog::new("torn");
+        log.open().append_all(&[set("a"), set("b")]).unwrap();
+        let whole = log.len();
+        let first = encode_record(&set("a")).unwrap().len() as u64;
+        for cut in [1, RECORD_HEADER_LEN as u64 + 1, whole - first - 1] {
+            log.open().append(&set("b")).unwrap();
+            let torn = whole - cut;
+            OpenOptions::new()
+                .write(true)
+                .open(&log.0)
+                .unwrap()
+                .set_len(torn)
+                .unwrap();
+            let (ids, report) = log.replay().unwrap();
+            assert_eq!(ids, ["a"]);
+            assert_eq!(report.truncated, torn - first);
+            assert_eq!(log.len(), first);
+        }
+    }
+
+    #[test]
+    fn damage_before_the_end_is_an_error() {
+        let log = TempLog::new("damaged");
+        log.open().append_all(&[set("a"), set("b")]).unwrap();
+        let good = fs::read(&log.0).unwrap();
+
+        let mut bad_checksum = good.clone();
+        bad_checksum[RECORD_HEADER_LEN] ^= 0xff;
+        fs::write(&log.0, &bad_checksum).unwrap();
+        assert!(matches!(
+            log.replay(),
+            Err(AofError::InvalidRecord { offset: 0, .. })
+        ));
+
+        let mut bad_length = good;
+        bad_length[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&log.0, &bad_length).unwrap();
+        assert!(matches!(
+            log.replay(),
+            Err(AofError::InvalidRecord { offset: 0, .. })
+        ));
+        // Nothing is cut off a log that failed to replay.
+        assert_eq!(log.len(), bad_length.len() as u64);
+    }
+
+    #[test]
+    fn appends_after_a_rewrite_go_to_the_new_log() {
+        let log = TempLog::new("rewrite");
+        let aof = log.open();
+        aof.append_all(&[set("a"), set("b")]).unwrap();
+        aof.begin_rewrite(1).unwrap();
+        aof.append(&set("c")).unwrap();
+        let snapshot = Snapshot {
+            collections: vec![CollectionDump {
+                name: "c".to_string(),
+                objects: vec![ObjectDump {
+                    id: "b".to_string(),
+                    value: json!({"id": "b"}),
+                    expires_at: None,
+                    version: 1,
+                }],
+                indexes: Vec::new(),
+                schema: None,
+            }],
+        };
+        let size = aof.rewrite(&snapshot).unwrap();
+        assert_eq!(log.len(), size);
+        aof.append(&set("d")).unwrap();
+        let (ids, report) = log.replay().unwrap();
+        assert_eq!(ids, ["b", "c", "d"]);
+        assert_eq!(report.records, 4);
+    }
+
+    #[test]
+    fn a_failed_rewrite_leaves_no_temporary_log() {
+        let log = TempLog::new("failed-rewrite");
+        let aof = log.open();
+        aof.append(&set("a")).unwrap();
+        // A directory in the log's place makes the rename at the end fail.
+        fs::remove_file(&log.0).unwrap();
+        fs::create_dir_all(log.0.join("in-the-way")).unwrap();
+        aof.begin_rewrite(0).unwrap();
+        let rewritten = aof.rewrite(&Snapshot {
+            collections: Vec::new(),
+        });
+        fs::remove_dir_all(&log.0).unwrap();
+        assert!(rewritten.is_err());
+        assert!(!aof.rewrite_path().exists());
+        aof.begin_rewrite(0).unwrap();
+    }
+
+    #[test]
+    fn a_large_damaged_tail_is_cut_off() {
+        let log = TempLog::new("large-tail");
+        log.open().append(&set("a")).unwrap();
+        let first = log.len();
+        let mut tail = Vec::from(u32::MAX.to_le_bytes());
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        tail.extend((0..4 << 20).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }));
+        OpenOptions::new()
+            .append(true)
+            .open(&log.0)
+            .unwrap()
+            .write_all(&tail)
+            .unwrap();
+        let (ids, report) = log.replay().unwrap();
+        assert_eq!(ids, ["a"]);
+        assert_eq!(report.truncated, tail.len() as u64);
+        assert_eq!(log.len(), first);
+    }
+}
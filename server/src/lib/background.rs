@@ -0,0 +1,37 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, error, info};
+
+use crate::keyspace::Keyspace;
+
+/// Housekeeping that runs once a second for as long as the server is up.
+pub async fn run_background_tasks(kv: Arc<Keyspace>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+        let log = kv.clone();
+        match tokio::task::spawn_blocking(move || log.sync_log()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Failed to sync append-only log: {}", e),
+            Err(e) => error!("Failed to sync append-only log: {}", e),
+        }
+        let expiring = kv.clone();
+        match tokio::task::spawn_blocking(move || expiring.remove_expired()).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(removed)) => debug!("Removed {} expired objects", removed),
+            Ok(Err(e)) => error!("Failed to remove expired objects: {}", e),
+            Err(e) => error!("Failed to remove expired objects: {}", e),
+        }
+        if kv.log_needs_rewrite() {
+            info!("Append-only log grew past its rewrite threshold, rewriting");
+            // Taking the snapshot holds the whole keyspace, so it runs off the runtime too.
+            let rewriting = kv.clone();
+            match tokio::task::spawn_blocking(move || rewriting.start_log_rewrite()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to start append-only log rewrite: {}", e),
+                Err(e) => error!("Failed to start append-only log rewrite: {}", e),
+            }
+        }
+    }
+}
@@ -1,214 +1,1458 @@
-use std::{collections, f32::consts::E, hash::DefaultHasher};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use common::message::{Command, Response};
-use dashmap::{DashMap, Entry};
-use serde_json::{json, Value};
-use std::hash::{Hash, Hasher};
+use serde_json::{json, Map, Number, Value};
 use thiserror::Error;
 use tokio::sync::mpsc::{error::SendError, Receiver, Sender};
-use tracing::{error, info};
-use ulid::Ulid;
+use tracing::error;
+
+use crate::aggregate::Aggregation;
+use crate::index::{IndexKind, IndexSpec};
+use crate::keyspace::{
+    check_version, now_millis, Keyspace, LoadMode, Object, Range, StoreError, Undo,
+};
+use crate::patch::{self, json_eq, PatchMode};
+use crate::pointer::{unescape, Pointer, PointerError};
+use crate::query::{field_path, Filter, Projection, Sort};
+use crate::schema::Schema;
+use crate::snapshot::Snapshot;
 
 pub struct DataStore {
-    kv: DashMap<String, DashMap<Ulid, Value>>,
+    kv: Arc<Keyspace>,
     tx: Sender<Response>,
     rx: Receiver<Command>,
+    /// Commands queued since MULTI, if one is open.
+    transaction: Option<Transaction>,
+    /// Objects the next EXEC checks, as WATCH found them.
+    watched: Vec<Watch>,
 }
 
-#[derive(Debug, Error)]
-pub enum DSError {
-    #[error("Parse error occurred")]
-    ParseError,
+/// Commands queued between MULTI and EXEC.
+#[derive(Default)]
+struct Transaction {
+    commands: Vec<Command>,
+    /// Set when a command couldn't be queued; EXEC then runs none of them.
+    failed: bool,
+    /// Collections that queued commands create, which later ones may use.
+    created: HashSet<String>,
+}
 
-    #[error("Read Error")]
-    ReadError,
+/// A watched object and the version it had, or `None` if it didn't exist.
+struct Watch {
+    uri: String,
+    name: String,
+    id: String,
+    version: Option<u64>,
+}
 
+#[derive(Debug, Error)]
+pub enum DSError {
     #[error("send to data task error {0}")]
     SendError(SendError<Response>),
 }
 
 impl DataStore {
-    pub fn new(tx: Sender<Response>, rx: Receiver<Command>) -> Self {
-        let kv = DashMap::new();
-        Self { kv, tx, rx }
+    pub fn new(kv: Arc<Keyspace>, tx: Sender<Response>, rx: Receiver<Command>) -> Self {
+        Self {
+            kv,
+            tx,
+            rx,
+            transaction: None,
+            watched: Vec::new(),
+        }
     }
 
     pub async fn run(mut self) -> Result<(), DSError> {
         while let Some(msg) = self.rx.recv().await {
-            match msg {
-                Command::PING { headers } => {
-                    self.send_response(Response::PONG).await;
+            // Responses are built synchronously so no map guard is ever held across an await.
+            // Commands that take the keyspace gate exclusively must not run under the shared
+            // hold `execute` takes.
+            let response = match msg {
+                Command::MULTI { .. } if self.transaction.is_some() => {
+                    Response::ERROR("MULTI calls can't be nested".to_string())
                 }
-                Command::POST { uri, body, headers } => {
-                    let (name, _) = uri.split_once('/').unwrap_or((uri.as_str(), ""));
-                    let id = Ulid::new();
-                    if let Some(ds) = self.kv.get_mut(name) {
-                        ds.insert(id, body);
-                    } else {
-                        let collection = DashMap::from_iter(vec![(id.clone(), body)]);
-                        self.kv.insert(name.to_string(), collection);
-                    }
-
-                    self.send_response(Response::ID(id.to_string())).await;
+                Command::MULTI { .. } => {
+                    self.transaction = Some(Transaction::default());
+                    Response::OK
                 }
-                Command::GET { uri, headers } => {
-                    let (name, id) = uri.split_once('/').unwrap_or((uri.as_str(), ""));
-                    if id.is_empty() {
-                        let collection = self.kv.get(name);
-                        if collection.is_none() {
-                            self.send_response(Response::ERROR("collection not found".to_string()))
-                                .await;
-                        } else {
-                            let collection = collection.unwrap();
-                            let res = Response::COLLECTION(
-                                collection
-                                    .value()
-                                    .iter()
-                                    .map(|r| {
-                                        json!({
-                                            "ID": r.key().to_string(),
-                                            "value": r.value().clone()
-                                        })
-                                    })
-                                    .collect::<Vec<_>>(),
-                            );
-                            self.send_response(res).await;
-                        }
-                    } else {
-                        let Some(collection) = self.kv.get(name) else {
-                            self.send_response(Response::ERROR("collection not found".to_string()))
-                                .await;
-                            continue;
-                        };
-                        let Ok(ulid) = Ulid::from_string(id) else {
-                            self.send_response(Response::ERROR("invalid ID".to_string()))
-                                .await;
-                            continue;
-                        };
-                        let Some(obj) = collection.get(&ulid) else {
-                            self.send_response(Response::ERROR("object not found".to_string()))
-                                .await;
-                            continue;
-                        };
-
-                        self.send_response(Response::OBJECT(json!({
-                            "ID": obj.key().to_string(),
-                            "value": obj.clone()
-                        })))
-                        .await;
+                Command::EXEC { .. } => self.exec(),
+                Command::DISCARD { .. } => match self.transaction.take() {
+                    Some(_) => {
+                        self.watched.clear();
+                        Response::OK
                     }
+                    None => Response::ERROR("DISCARD without MULTI".to_string()),
+                },
+                Command::WATCH { .. } if self.transaction.is_some() => {
+                    Response::ERROR("WATCH inside MULTI isn't allowed".to_string())
                 }
-                Command::PUT {
-                    ref uri,
-                    ref body,
-                    headers,
-                } => {
-                    let Some((name, id)) = uri.split_once("/") else {
-                        self.send_response(Response::ERROR("invalid path".to_string()))
-                            .await;
-                        continue;
-                    };
-                    let Some(collection) = self.kv.get_mut(name) else {
-                        self.send_response(Response::ERROR("collection not found".to_string()))
-                            .await;
-                        continue;
-                    };
-                    let Ok(id) = Ulid::from_string(id) else {
-                        self.send_response(Response::ERROR("invalid id".to_string()))
-                            .await;
-                        continue;
-                    };
-                    let Entry::Occupied(_) = collection.entry(id).and_modify(|v| *v = body.clone())
-                    else {
-                        self.send_response(Response::ERROR("object not found".to_string()))
-                            .await;
-                        continue;
-                    };
-                    self.send_response(Response::OK).await;
+                Command::WATCH { uri, .. } => self.watch(uri),
+                command if self.transaction.is_some() => self.queue(command),
+                Command::DUMP { file } => self.dump(file).await,
+                Command::LOAD { file, headers } => self.load(file, headers).await,
+                Command::REWRITELOG { .. } => match self.kv.start_log_rewrite() {
+                    Ok(()) => Response::OK,
+                    Err(e) => Response::ERROR(e.to_string()),
+                },
+                Command::DROP { uri, .. } => match collection_name(&uri) {
+                    Ok(name) => match self.kv.drop_collection(name) {
+                        Ok(removed) => Response::OBJECT(json!({ "removed": removed })),
+                        Err(e) => Response::ERROR(e.to_string()),
+                    },
+                    Err(e) => Response::ERROR(e),
+                },
+                Command::CLEAR { uri, .. } => match collection_name(&uri) {
+                    Ok(name) => match self.kv.clear_collection(name) {
+                        Ok(removed) => Response::OBJECT(json!({ "removed": removed })),
+                        Err(e) => Response::ERROR(e.to_string()),
+                    },
+                    Err(e) => Response::ERROR(e),
+                },
+                Command::RENAME { uri, body, .. } => self.rename(&uri, &body),
+                Command::INDEX { uri, body, .. } => self.create_index(&uri, &body),
+                Command::DROPINDEX { uri, body, .. } => self.drop_index(&uri, &body),
+                Command::SETSCHEMA { uri, body, .. } => self.set_schema(&uri, body),
+                Command::BATCH { commands, headers } => self.batch(commands, &headers),
+                Command::INSERT { uri, body, headers } => self.insert_many(uri, body, headers),
+                command => self.execute(command),
+            };
+            self.send_response(response).await?;
+        }
+        Ok(())
+    }
+
+    fn execute(&self, command: Command) -> Response {
+        let _shared = self.kv.shared();
+        self.apply(command)
+    }
+
+    /// Runs a command under a hold on the keyspace gate that the caller already has.
+    fn apply(&self, command: Command) -> Response {
+        match command {
+            Command::PING { .. } => Response::PONG,
+            Command::INFO { .. } => Response::OBJECT(self.kv.info()),
+            Command::POST { uri, body, headers } => {
+                let (name, _) = uri.split_once('/').unwrap_or((uri.as_str(), ""));
+                let expires_at = match ttl_header(&headers) {
+                    Ok(expires_at) => expires_at,
+                    Err(e) => return Response::ERROR(e),
+                };
+                match self.kv.insert(name, body, expires_at) {
+                    Ok(id) => Response::ID(id),
+                    Err(e) => Response::ERROR(e.to_string()),
                 }
-                Command::DELETE { ref uri, headers } => {
-                    let Some((name, id)) = uri.split_once("/") else {
-                        self.send_response(Response::ERROR("invalid path".to_string()))
-                            .await;
-                        continue;
+            }
+            Command::GET { uri, headers } => {
+                let projection = match Projection::from_headers(&headers) {
+                    Ok(projection) => projection,
+                    Err(e) => return Response::ERROR(e.to_string()),
+                };
+                let sort = match Sort::from_headers(&headers) {
+                    Ok(sort) => sort,
+                    Err(e) => return Response::ERROR(e.to_string()),
+                };
+                let (name, key) = uri.split_once('/').unwrap_or((uri.as_str(), ""));
+                if key.is_empty() {
+                    let filter = match Filter::from_headers(&headers) {
+                        Ok(filter) => filter,
+                        Err(e) => return Response::ERROR(e.to_string()),
                     };
-                    let Some(collection) = self.kv.get_mut(name) else {
-                        self.send_response(Response::ERROR("collection not found".to_string()))
-                            .await;
-                        continue;
+                    let mut range = match range_headers(&headers) {
+                        Ok(range) => range,
+                        Err(e) => return Response::ERROR(e),
                     };
-                    let Ok(id) = Ulid::from_string(id) else {
-                        self.send_response(Response::ERROR("invalid id".to_string()))
-                            .await;
-                        continue;
+                    let Some(sort) = sort else {
+                        let page = match self.kv.list(name, &filter, &range, |id, object| {
+                            envelope(id, object, &projection)
+                        }) {
+                            Ok(page) => page,
+                            Err(e) => return Response::ERROR(e.to_string()),
+                        };
+                        // Only a read that asked for pages gets a cursor back.
+                        return if range.limit.is_some() || range.cursor.is_some() {
+                            Response::PAGE {
+                                items: page.items,
+                                cursor: page.next.as_deref().map(encode_cursor),
+                            }
+                        } else {
+                            Response::COLLECTION(page.items)
+                        };
                     };
-                    let Some(_) = collection.remove(&id) else {
-                        self.send_response(Response::ERROR("object not found".to_string()))
-                            .await;
-                        continue;
+                    if range.cursor.is_some() {
+                        return Response::ERROR("a sorted read can't use a cursor".to_string());
+                    }
+                    // Sorting needs every match, so the limit only cuts the sorted result.
+                    let limit = range.limit.take();
+                    let mut items = match self.kv.list(name, &filter, &range, |id, object| {
+                        (sort.keys(&object.value), envelope(id, object, &projection))
+                    }) {
+                        Ok(page) => page.items,
+                        Err(e) => return Response::ERROR(e.to_string()),
                     };
-                    self.send_response(Response::OK).await;
-                }
-                Command::PATCH {
-                    ref uri,
-                    ref body,
-                    headers,
-                } => {
-                    let Some((name, id)) = uri.split_once("/") else {
-                        self.send_response(Response::ERROR("invalid path".to_string()))
-                            .await;
-                        continue;
+                    items.sort_by(|(a, _), (b, _)| sort.compare(a, b));
+                    items.truncate(limit.unwrap_or(usize::MAX));
+                    Response::COLLECTION(items.into_iter().map(|(_, item)| item).collect())
+                } else {
+                    let (name, id, pointer) = match object_path(&uri) {
+                        Ok(path) => path,
+                        Err(e) => return Response::ERROR(e),
                     };
-                    let Some(collection) = self.kv.get_mut(name) else {
-                        self.send_response(Response::ERROR("collection not found".to_string()))
-                            .await;
-                        continue;
+                    let mut object = match self.kv.get(name, &id) {
+                        Ok(object) => object,
+                        Err(e) => return Response::ERROR(e.to_string()),
                     };
-                    let Ok(id) = Ulid::from_string(id) else {
-                        self.send_response(Response::ERROR("invalid id".to_string()))
-                            .await;
-                        continue;
+                    let Some(value) = pointer.get_mut(&mut object.value).map(Value::take) else {
+                        return Response::ERROR(PointerError::NotFound(pointer).to_string());
                     };
-                    let Some(mut object) = collection.get_mut(&id) else {
-                        self.send_response(Response::ERROR("object not found".to_string()))
-                            .await;
-                        continue;
+                    let value = match shape(value, sort.as_ref(), &projection) {
+                        Ok(value) => value,
+                        Err(e) => return Response::ERROR(e),
                     };
-                    let v = object.take();
-                    drop(object);
-                    match (v, body) {
-                        (Value::Null, Value::Null)
-                        | (Value::Bool(_), Value::Bool(_))
-                        | (Value::Number(_), Value::Number(_))
-                        | (Value::String(_), Value::String(_)) => {
-                            collection.insert(id, body.clone());
-                        }
-                        (Value::Array(mut a), Value::Array(b)) => {
-                            a.extend(b.clone());
-                            collection.insert(id, Value::Array(a));
-                        }
-                        (Value::Object(mut a), Value::Object(b)) => {
-                            a.extend(b.clone());
-                            collection.insert(id, Value::Object(a));
+                    if pointer.is_root() {
+                        Response::OBJECT(json!({
+                            "ID": id,
+                            "value": value,
+                            "version": object.version
+                        }))
+                    } else {
+                        Response::OBJECT(value)
+                    }
+                }
+            }
+            Command::PUT { uri, body, headers } => {
+                let (name, id, pointer) = match object_path(&uri) {
+                    Ok(path) => path,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let expires_at = match ttl_header(&headers) {
+                    Ok(expires_at) => expires_at,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let exists = match headers.as_ref().and_then(|h| h.get("exists")) {
+                    None => None,
+                    Some(Value::Bool(exists)) => Some(*exists),
+                    Some(exists) => {
+                        return Response::ERROR(format!("invalid exists header {}", exists))
+                    }
+                };
+                let if_match = match if_match_header(&headers) {
+                    Ok(if_match) => if_match,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let result = if pointer.is_root() {
+                    // Like a fresh write, PUT drops any earlier expiry unless it sets a new one.
+                    let object = Object::new(body, expires_at);
+                    match exists {
+                        Some(true) => self.kv.modify(name, &id, |current| {
+                            check_version(Some(current), if_match)?;
+                            Ok(object)
+                        }),
+                        _ => self.kv.upsert(name, &id, |current| {
+                            check_version(current, if_match)?;
+                            if exists == Some(false) && current.is_some() {
+                                return Err(StoreError::AlreadyExists);
+                            }
+                            Ok(object)
+                        }),
+                    }
+                } else if expires_at.is_some() {
+                    return Response::ERROR("ttl applies to whole objects".to_string());
+                } else {
+                    self.kv.modify(name, &id, |object| {
+                        check_version(Some(object), if_match)?;
+                        let mut value = object.value.clone();
+                        match (exists, pointer.get(&value)) {
+                            (Some(true), None) => {
+                                return Err(PointerError::NotFound(pointer).into())
+                            }
+                            (Some(false), Some(_)) => {
+                                return Err(PointerError::Exists(pointer).into())
+                            }
+                            _ => {}
                         }
-                        _ => {
-                            self.send_response(Response::ERROR("type mismatch".to_string()))
-                                .await;
-                            continue;
+                        pointer.set(&mut value, body)?;
+                        Ok(Object::new(value, object.expires_at))
+                    })
+                };
+                match result {
+                    Ok(version) => Response::OBJECT(json!({ "ID": id, "version": version })),
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::DELETE { uri, headers } => {
+                let (name, id, pointer) = match object_path(&uri) {
+                    Ok(path) => path,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let if_match = match if_match_header(&headers) {
+                    Ok(if_match) => if_match,
+                    Err(e) => return Response::ERROR(e),
+                };
+                if pointer.is_root() {
+                    return match self.kv.remove(name, &id, if_match) {
+                        Ok(()) => Response::OK,
+                        Err(e) => Response::ERROR(e.to_string()),
+                    };
+                }
+                let result = self.kv.modify(name, &id, |object| {
+                    check_version(Some(object), if_match)?;
+                    let mut value = object.value.clone();
+                    pointer.remove(&mut value)?;
+                    Ok(Object::new(value, object.expires_at))
+                });
+                match result {
+                    Ok(version) => Response::OBJECT(json!({ "ID": id, "version": version })),
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::PATCH { uri, body, headers } => {
+                let (name, id, pointer) = match object_path(&uri) {
+                    Ok(path) => path,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let mode = match patch_mode(&headers) {
+                    Ok(mode) => mode,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let if_match = match if_match_header(&headers) {
+                    Ok(if_match) => if_match,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let mut patched = Value::Null;
+                let result = self.kv.modify(name, &id, |object| {
+                    check_version(Some(object), if_match)?;
+                    let mut value = object.value.clone();
+                    let target = pointer.resolve_mut(&mut value)?;
+                    match mode {
+                        PatchMode::Merge => patch::merge(target, body),
+                        PatchMode::JsonPatch => patch::apply(target, body)?,
+                        PatchMode::Append => patch::append(target, body)?,
+                    }
+                    patched = target.clone();
+                    Ok(Object::new(value, object.expires_at))
+                });
+                match result {
+                    Ok(version) => written(&id, &pointer, version, json!({ "value": patched })),
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::EXPIRE { uri, body, .. } => {
+                let (name, id) = match object_key(&uri) {
+                    Ok(key) => key,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let Some(expires_at) = expires_at(&body) else {
+                    return Response::ERROR("invalid ttl".to_string());
+                };
+                match self.kv.set_expiry(name, &id, Some(expires_at)) {
+                    Ok(()) => Response::OK,
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::PERSIST { uri, .. } => {
+                let (name, id) = match object_key(&uri) {
+                    Ok(key) => key,
+                    Err(e) => return Response::ERROR(e),
+                };
+                match self.kv.set_expiry(name, &id, None) {
+                    Ok(()) => Response::OK,
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::TTL { uri, .. } => {
+                let (name, id) = match object_key(&uri) {
+                    Ok(key) => key,
+                    Err(e) => return Response::ERROR(e),
+                };
+                match self.kv.get(name, &id) {
+                    // Remaining seconds, rounded up so a live object never reports 0.
+                    Ok(Object {
+                        expires_at: Some(at),
+                        ..
+                    }) => Response::OBJECT(json!(at.saturating_sub(now_millis()).div_ceil(1000))),
+                    Ok(_) => Response::NULL,
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::INCR { uri, headers } => self.increment(&uri, json!(1), &headers),
+            Command::INCRBY { uri, body, headers } => self.increment(&uri, body, &headers),
+            Command::PUSH { uri, body, headers } => self.push(&uri, body, &headers),
+            Command::POP { uri, headers } => self.pop(&uri, &headers),
+            Command::PULL { uri, body, headers } => self.pull(&uri, body, &headers),
+            Command::COLLECTIONS { .. } => Response::COLLECTION(
+                self.kv
+                    .collections()
+                    .into_iter()
+                    .map(|collection| {
+                        json!({
+                            "name": collection.name,
+                            "objects": collection.objects,
+                            "bytes": collection.bytes
+                        })
+                    })
+                    .collect(),
+            ),
+            Command::AGGREGATE { uri, body, headers } => {
+                let name = match collection_name(&uri) {
+                    Ok(name) => name,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let mut aggregation = match Aggregation::from_body(&body) {
+                    Ok(aggregation) => aggregation,
+                    Err(e) => return Response::ERROR(e.to_string()),
+                };
+                let filter = match Filter::from_headers(&headers) {
+                    Ok(filter) => filter,
+                    Err(e) => return Response::ERROR(e.to_string()),
+                };
+                let range = match created_range(&headers) {
+                    Ok(range) => range,
+                    Err(e) => return Response::ERROR(e),
+                };
+                match self.kv.list(name, &filter, &range, |_, object| {
+                    aggregation.add(&object.value)
+                }) {
+                    Ok(_) => Response::OBJECT(aggregation.finish()),
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::PURGE { uri, headers } => {
+                let name = match collection_name(&uri) {
+                    Ok(name) => name,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let cutoff = match purge_cutoff(&headers) {
+                    Ok(cutoff) => cutoff,
+                    Err(e) => return Response::ERROR(e),
+                };
+                match self.kv.purge(name, cutoff) {
+                    Ok(removed) => Response::OBJECT(json!({ "removed": removed })),
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::COUNT { uri, headers } => {
+                let name = match collection_name(&uri) {
+                    Ok(name) => name,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let filter = match Filter::from_headers(&headers) {
+                    Ok(filter) => filter,
+                    Err(e) => return Response::ERROR(e.to_string()),
+                };
+                match self.kv.count(name, &filter) {
+                    Ok(count) => Response::OBJECT(json!(count)),
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::INDEXES { uri, .. } => {
+                let name = match collection_name(&uri) {
+                    Ok(name) => name,
+                    Err(e) => return Response::ERROR(e),
+                };
+                match self.kv.indexes(name) {
+                    Ok(indexes) => Response::COLLECTION(
+                        indexes
+                            .into_iter()
+                            .map(|index| {
+                                json!({
+                                    "path": index.spec.path,
+                                    "kind": index.spec.kind,
+                                    "unique": index.spec.unique,
+                                    "keys": index.keys
+                                })
+                            })
+                            .collect(),
+                    ),
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::MGET { uri, body, headers } => {
+                let name = match collection_name(&uri) {
+                    Ok(name) => name,
+                    Err(e) => return Response::ERROR(e),
+                };
+                let projection = match Projection::from_headers(&headers) {
+                    Ok(projection) => projection,
+                    Err(e) => return Response::ERROR(e.to_string()),
+                };
+                let keys = match body {
+                    Value::Array(keys) if keys.iter().all(Value::is_string) => keys,
+                    _ => return Response::ERROR("expected an array of keys".to_string()),
+                };
+                // Missing objects come back as null so items line up with the keys asked for.
+                Response::COLLECTION(
+                    keys.iter()
+                        .filter_map(Value::as_str)
+                        .map(|id| match self.kv.get(name, id) {
+                            Ok(object) => envelope(id, &object, &projection),
+                            Err(_) => Value::Null,
+                        })
+                        .collect(),
+                )
+            }
+            Command::GETSCHEMA { uri, .. } => {
+                let name = match collection_name(&uri) {
+                    Ok(name) => name,
+                    Err(e) => return Response::ERROR(e),
+                };
+                match self.kv.schema(name) {
+                    Ok(Some(schema)) => Response::OBJECT(schema),
+                    Ok(None) => Response::NULL,
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::VALIDATE { uri, headers } => {
+                let name = match collection_name(&uri) {
+                    Ok(name) => name,
+                    Err(e) => return Response::ERROR(e),
+                };
+                // A schema in the headers is tried out instead of the collection's own.
+                let schema = match headers.and_then(|mut h| h.remove("schema")) {
+                    Some(schema) => match Schema::compile(schema) {
+                        Ok(schema) => Some(schema),
+                        Err(e) => return Response::ERROR(e.to_string()),
+                    },
+                    None => None,
+                };
+                match self.kv.validate(name, schema) {
+                    Ok(report) => Response::OBJECT(json!({
+                        "checked": report.checked,
+                        "invalid": report
+                            .invalid
+                            .into_iter()
+                            .map(|(id, violations)| json!({
+                                "ID": id,
+                                "errors": violations
+                                    .into_iter()
+                                    .map(|v| json!({ "path": v.path.encoded(), "message": v.message }))
+                                    .collect::<Vec<_>>()
+                            }))
+                            .collect::<Vec<_>>()
+                    })),
+                    Err(e) => Response::ERROR(e.to_string()),
+                }
+            }
+            Command::DUMP { .. }
+            | Command::LOAD { .. }
+            | Command::REWRITELOG { .. }
+            | Command::DROP { .. }
+            | Command::CLEAR { .. }
+            | Command::RENAME { .. }
+            | Command::INDEX { .. }
+            | Command::DROPINDEX { .. }
+            | Command::SETSCHEMA { .. }
+            | Command::BATCH { .. }
+            | Command::INSERT { .. }
+            | Command::MULTI { .. }
+            | Command::EXEC { .. }
+            | Command::DISCARD { .. }
+            | Command::WATCH { .. } => {
+                // `run` handles these itself, so only a batch or transaction gets here.
+                Response::ERROR(NOT_BATCHABLE.to_string())
+            }
+        }
+    }
+
+    /// Adds `amount` to the number at `uri`, creating the object or field if it is missing.
+    fn increment(
+        &self,
+        uri: &str,
+        amount: Value,
+        headers: &Option<Map<String, Value>>,
+    ) -> Response {
+        let (name, id, pointer) = match object_path(uri) {
+            Ok(path) => path,
+            Err(e) => return Response::ERROR(e),
+        };
+        let Value::Number(amount) = amount else {
+            return Response::ERROR("the increment must be a number".to_string());
+        };
+        // A missing field counts as this before the amount is added.
+        let initial = match headers.as_ref().and_then(|h| h.get("initial")) {
+            None => Number::from(0),
+            Some(Value::Number(initial)) => initial.clone(),
+            Some(initial) => return Response::ERROR(format!("invalid initial header {}", initial)),
+        };
+        let if_match = match if_match_header(headers) {
+            Ok(if_match) => if_match,
+            Err(e) => return Response::ERROR(e),
+        };
+        let mut result = Value::Null;
+        let version = self.kv.upsert(name, &id, |current| {
+            check_version(current, if_match)?;
+            let (mut value, expires_at) = match current {
+                Some(object) => (object.value.clone(), object.expires_at),
+                None => (Value::Null, None),
+            };
+            let target = pointer.get_or_create(&mut value)?;
+            let start = match target {
+                Value::Null => &initial,
+                Value::Number(n) => n,
+                _ => return Err(StoreError::NotANumber(pointer.clone())),
+            };
+            *target = Value::Number(add(start, &amount)?);
+            result = target.clone();
+            Ok(Object::new(value, expires_at))
+        });
+        match version {
+            Ok(version) => written(&id, &pointer, version, json!({ "value": result })),
+            Err(e) => Response::ERROR(e.to_string()),
+        }
+    }
+
+    /// Inserts `element` into the array at `uri`, creating the object or array if it is
+    /// missing, and replies with the array's new length.
+    ///
+    /// With the `unique` header set, an element that is already in the array isn't added
+    /// again, which gives the array set semantics.
+    fn push(&self, uri: &str, element: Value, headers: &Option<Map<String, Value>>) -> Response {
+        let (name, id, pointer) = match object_path(uri) {
+            Ok(path) => path,
+            Err(e) => return Response::ERROR(e),
+        };
+        let at = match position_header(headers) {
+            Ok(at) => at,
+            Err(e) => return Response::ERROR(e),
+        };
+        let unique = match headers.as_ref().and_then(|h| h.get("unique")) {
+            None => false,
+            Some(Value::Bool(unique)) => *unique,
+            Some(unique) => return Response::ERROR(format!("invalid unique header {}", unique)),
+        };
+        let if_match = match if_match_header(headers) {
+            Ok(if_match) => if_match,
+            Err(e) => return Response::ERROR(e),
+        };
+        let mut length = 0;
+        let version = self.kv.upsert(name, &id, |current| {
+            check_version(current, if_match)?;
+            let (mut value, expires_at) = match current {
+                Some(object) => (object.value.clone(), object.expires_at),
+                None => (Value::Null, None),
+            };
+            let target = pointer.get_or_create(&mut value)?;
+            if target.is_null() {
+                *target = Value::Array(Vec::new());
+            }
+            let Value::Array(array) = target else {
+                return Err(StoreError::NotAnArray(pointer.clone()));
+            };
+            if !(unique && array.iter().any(|e| json_eq(e, &element))) {
+                let i = match at {
+                    Position::Front => 0,
+                    Position::Back => array.len(),
+                    Position::Index(i) if i <= array.len() => i,
+                    Position::Index(i) => return Err(StoreError::IndexOutOfRange(i)),
+                };
+                array.insert(i, element);
+            }
+            length = array.len();
+            Ok(Object::new(value, expires_at))
+        });
+        match version {
+            Ok(version) => written(&id, &pointer, version, json!({ "length": length })),
+            Err(e) => Response::ERROR(e.to_string()),
+        }
+    }
+
+    /// Takes an element out of the array at `uri` and replies with it, or with NULL if the
+    /// array is empty.
+    fn pop(&self, uri: &str, headers: &Option<Map<String, Value>>) -> Response {
+        let (name, id, pointer) = match object_path(uri) {
+            Ok(path) => path,
+            Err(e) => return Response::ERROR(e),
+        };
+        let at = match position_header(headers) {
+            Ok(at) => at,
+            Err(e) => return Response::ERROR(e),
+        };
+        let if_match = match if_match_header(headers) {
+            Ok(if_match) => if_match,
+            Err(e) => return Response::ERROR(e),
+        };
+        let mut element = Value::Null;
+        let version = self.kv.modify(name, &id, |object| {
+            check_version(Some(object), if_match)?;
+            let mut value = object.value.clone();
+            let Value::Array(array) = pointer.resolve_mut(&mut value)? else {
+                return Err(StoreError::NotAnArray(pointer.clone()));
+            };
+            element = match at {
+                Position::Front if !array.is_empty() => array.remove(0),
+                Position::Back => array.pop().ok_or(StoreError::EmptyArray)?,
+                Position::Index(i) if i < array.len() => array.remove(i),
+                Position::Index(i) => return Err(StoreError::IndexOutOfRange(i)),
+                Position::Front => return Err(StoreError::EmptyArray),
+            };
+            Ok(Object::new(value, object.expires_at))
+        });
+        match version {
+            Ok(version) => written(&id, &pointer, version, json!({ "value": element })),
+            Err(StoreError::EmptyArray) => Response::NULL,
+            Err(e) => Response::ERROR(e.to_string()),
+        }
+    }
+
+    /// Removes every element equal to `element` from the array at `uri`.
+    fn pull(&self, uri: &str, element: Value, headers: &Option<Map<String, Value>>) -> Response {
+        let (name, id, pointer) = match object_path(uri) {
+            Ok(path) => path,
+            Err(e) => return Response::ERROR(e),
+        };
+        let if_match = match if_match_header(headers) {
+            Ok(if_match) => if_match,
+            Err(e) => return Response::ERROR(e),
+        };
+        let (mut removed, mut length) = (0, 0);
+        let version = self.kv.modify(name, &id, |object| {
+            check_version(Some(object), if_match)?;
+            let mut value = object.value.clone();
+            let Value::Array(array) = pointer.resolve_mut(&mut value)? else {
+                return Err(StoreError::NotAnArray(pointer.clone()));
+            };
+            let before = array.len();
+            array.retain(|e| !json_eq(e, &element));
+            (removed, length) = (before - array.len(), array.len());
+            Ok(Object::new(value, object.expires_at))
+        });
+        match version {
+            Ok(version) => written(
+                &id,
+                &pointer,
+                version,
+                json!({ "removed": removed, "length": length }),
+            ),
+            Err(e) => Response::ERROR(e.to_string()),
+        }
+    }
+
+    fn rename(&self, uri: &str, to: &Value) -> Response {
+        let from = match collection_name(uri) {
+            Ok(name) => name,
+            Err(e) => return Response::ERROR(e),
+        };
+        let to = match to.as_str().map(collection_name) {
+            Some(Ok(name)) => name,
+            Some(Err(e)) => return Response::ERROR(e),
+            None => return Response::ERROR("the new name must be a string".to_string()),
+        };
+        match self.kv.rename_collection(from, to) {
+            Ok(()) => Response::OK,
+            Err(e) => Response::ERROR(e.to_string()),
+        }
+    }
+
+    fn create_index(&self, uri: &str, body: &Value) -> Response {
+        let name = match collection_name(uri) {
+            Ok(name) => name,
+            Err(e) => return Response::ERROR(e),
+        };
+        let (spec, path) = match index_spec(body) {
+            Ok(index) => index,
+            Err(e) => return Response::ERROR(e),
+        };
+        match self.kv.create_index(name, spec, path) {
+            Ok(()) => Response::OK,
+            Err(e) => Response::ERROR(e.to_string()),
+        }
+    }
+
+    fn drop_index(&self, uri: &str, path: &Value) -> Response {
+        let name = match collection_name(uri) {
+            Ok(name) => name,
+            Err(e) => return Response::ERROR(e),
+        };
+        let path = match path.as_str().map(field_path) {
+            Some(Ok(path)) => path,
+            Some(Err(e)) => return Response::ERROR(e),
+            None => return Response::ERROR("the index path must be a string".to_string()),
+        };
+        match self.kv.drop_index(name, &path) {
+            Ok(()) => Response::OK,
+            Err(e) => Response::ERROR(e.to_string()),
+        }
+    }
+
+    /// Runs the commands of a batch in order.
+    ///
+    /// Without the `atomic` header every command stands on its own. With it, nothing else
+    /// runs until the batch is done, and if any command fails the ones before it are
+    /// undone and the batch fails as a whole.
+    fn batch(&self, commands: Vec<Command>, headers: &Option<Map<String, Value>>) -> Response {
+        let atomic = match headers.as_ref().and_then(|h| h.get("atomic")) {
+            None => false,
+            Some(Value::Bool(atomic)) => *atomic,
+            Some(atomic) => return Response::ERROR(format!("invalid atomic header {}", atomic)),
+        };
+        if !atomic {
+            let _shared = self.kv.shared();
+            return Response::BATCH(
+                commands
+                    .into_iter()
+                    .map(|command| {
+                        if batchable(&command) {
+                            self.apply(command)
+                        } else {
+                            Response::ERROR(NOT_BATCHABLE.to_string())
                         }
+                    })
+                    .collect(),
+            );
+        }
+        if let Some(i) = commands.iter().position(|command| !batchable(command)) {
+            return Response::ERROR(format!("command {}: {}", i, NOT_BATCHABLE));
+        }
+        let _exclusive = self.kv.exclusive();
+        self.apply_all(commands)
+    }
+
+    /// Applies `commands` in order so that all of them take effect or, once one fails,
+    /// none does. The caller already holds the keyspace through [`Keyspace::exclusive`].
+    fn apply_all(&self, commands: Vec<Command>) -> Response {
+        let mut undo = Undo::default();
+        let mut responses = Vec::with_capacity(commands.len());
+        for (i, command) in commands.into_iter().enumerate() {
+            let posted = match &command {
+                Command::POST { uri, .. } => {
+                    let (name, _) = uri.split_once('/').unwrap_or((uri.as_str(), ""));
+                    self.kv.save_collection(&mut undo, name);
+                    Some(name.to_string())
+                }
+                command => {
+                    if let Some((name, id, _)) = written_object(command) {
+                        self.kv.save(&mut undo, name, &id);
+                    }
+                    None
+                }
+            };
+            let response = self.apply(command);
+            match (&response, posted) {
+                (Response::ERROR(e), _) => {
+                    let e = format!("command {} failed, so none were applied: {}", i, e);
+                    return match self.kv.rollback(undo) {
+                        Ok(()) => Response::ERROR(e),
+                        Err(undo_error) => Response::ERROR(format!(
+                            "{}; undoing the batch failed: {}",
+                            e, undo_error
+                        )),
                     };
-                    self.send_response(Response::OK).await;
                 }
-                Command::DUMP { file } => {}
-                _ => todo!(),
+                (Response::ID(id), Some(name)) => undo.created(&name, id),
+                _ => {}
             }
+            responses.push(response);
         }
-        Ok(())
+        Response::BATCH(responses)
     }
 
-    async fn send_response(&self, response: Response) {
-        if let Err(e) = self.tx.send(response).await {
+    /// Records the version `uri` has now, for EXEC to compare against.
+    fn watch(&mut self, uri: String) -> Response {
+        let (name, id, _) = match object_path(&uri) {
+            Ok(path) => path,
+            Err(e) => return Response::ERROR(e),
+        };
+        let watch = Watch {
+            name: name.to_string(),
+            version: self.version(name, &id),
+            id,
+            uri,
+        };
+        self.watched.push(watch);
+        Response::OK
+    }
+
+    fn version(&self, name: &str, id: &str) -> Option<u64> {
+        self.kv.get(name, id).ok().map(|object| object.version)
+    }
+
+    /// Checks a command sent inside MULTI and keeps it for EXEC. A command that fails the
+    /// checks gets its error now and makes EXEC run nothing.
+    fn queue(&mut self, command: Command) -> Response {
+        let Some(mut transaction) = self.transaction.take() else {
+            return Response::ERROR("no transaction is open".to_string());
+        };
+        let response = match self.check_queued(&command, &transaction.created) {
+            Ok(creates) => {
+                transaction.created.extend(creates);
+                transaction.commands.push(command);
+                Response::QUEUED
+            }
+            Err(e) => {
+                transaction.failed = true;
+                Response::ERROR(e)
+            }
+        };
+        self.transaction = Some(transaction);
+        response
+    }
+
+    /// The errors a command would certainly fail with, found before it runs: ones it can't
+    /// run inside a transaction, malformed URIs and headers, and reads or changes in
+    /// collections that neither exist nor are created by an earlier queued command.
+    /// Returns the collection the command creates, if any.
+    fn check_queued(
+        &self,
+        command: &Command,
+        created: &HashSet<String>,
+    ) -> Result<Option<String>, String> {
+        if !batchable(command) {
+            return Err("this command can't be part of a transaction".to_string());
+        }
+        let (name, creates) = match command {
+            Command::POST { uri, .. } => (
+                uri.split_once('/').map_or(uri.as_str(), |(name, _)| name),
+                true,
+            ),
+            Command::PUT { uri, .. }
+            | Command::INCR { uri, .. }
+            | Command::INCRBY { uri, .. }
+            | Command::PUSH { uri, .. } => (object_path(uri)?.0, true),
+            Command::PATCH { uri, headers, .. } => {
+                patch_mode(headers)?;
+                (object_path(uri)?.0, false)
+            }
+            Command::DELETE { uri, .. }
+            | Command::EXPIRE { uri, .. }
+            | Command::PERSIST { uri, .. }
+            | Command::TTL { uri, .. }
+            | Command::POP { uri, .. }
+            | Command::PULL { uri, .. } => (object_path(uri)?.0, false),
+            Command::GET { uri, headers } => {
+                Projection::from_headers(headers).map_err(|e| e.to_string())?;
+                Sort::from_headers(headers).map_err(|e| e.to_string())?;
+                match uri.split_once('/') {
+                    Some((_, key)) if !key.is_empty() => (object_path(uri)?.0, false),
+                    _ => {
+                        Filter::from_headers(headers).map_err(|e| e.to_string())?;
+                        range_headers(headers)?;
+                        (
+                            uri.split_once('/').map_or(uri.as_str(), |(name, _)| name),
+                            false,
+                        )
+                    }
+                }
+            }
+            Command::COUNT { uri, headers } => {
+                Filter::from_headers(headers).map_err(|e| e.to_string())?;
+                (collection_name(uri)?, false)
+            }
+            Command::AGGREGATE { uri, body, headers } => {
+                Aggregation::from_body(body).map_err(|e| e.to_string())?;
+                Filter::from_headers(headers).map_err(|e| e.to_string())?;
+                created_range(headers)?;
+                (collection_name(uri)?, false)
+            }
+            Command::INDEXES { uri, .. }
+            | Command::GETSCHEMA { uri, .. }
+            | Command::VALIDATE { uri, .. }
+            | Command::MGET { uri, .. } => (collection_name(uri)?, false),
+            _ => return Ok(None),
+        };
+        if creates {
+            return Ok(Some(name.to_string()));
+        }
+        if !created.contains(name) && !self.kv.has_collection(name) {
+            return Err(StoreError::CollectionNotFound.to_string());
+        }
+        Ok(None)
+    }
+
+    /// Runs the queued commands all or nothing, unless a watched object has changed since
+    /// it was watched. Either way the transaction and the watches end.
+    fn exec(&mut self) -> Response {
+        let Some(transaction) = self.transaction.take() else {
+            return Response::ERROR("EXEC without MULTI".to_string());
+        };
+        let watched = std::mem::take(&mut self.watched);
+        if transaction.failed {
+            return Response::ERROR(
+                "the transaction was discarded because some of its commands couldn't be queued"
+                    .to_string(),
+            );
+        }
+        let _exclusive = self.kv.exclusive();
+        let changed: Vec<&str> = watched
+            .iter()
+            .filter(|watch| self.version(&watch.name, &watch.id) != watch.version)
+            .map(|watch| watch.uri.as_str())
+            .collect();
+        if !changed.is_empty() {
+            return Response::ERROR(format!(
+                "the transaction was aborted because watched objects changed: {}",
+                changed.join(", ")
+            ));
+        }
+        self.apply_all(transaction.commands)
+    }
+
+    /// Stores each value of an array under a fresh key, as a batch of POSTs would.
+    fn insert_many(
+        &self,
+        uri: String,
+        values: Value,
+        headers: Option<Map<String, Value>>,
+    ) -> Response {
+        let Value::Array(values) = values else {
+            return Response::ERROR("expected an array of values".to_string());
+        };
+        if let Err(e) = collection_name(&uri) {
+            return Response::ERROR(e);
+        }
+        let commands = values
+            .into_iter()
+            .map(|body| Command::POST {
+                uri: uri.clone(),
+                body,
+                headers: headers.clone(),
+            })
+            .collect();
+        self.batch(commands, &headers)
+    }
+
+    /// Attaches `schema` to a collection, or removes the schema when it is `null`.
+    fn set_schema(&self, uri: &str, schema: Value) -> Response {
+        let name = match collection_name(uri) {
+            Ok(name) => name,
+            Err(e) => return Response::ERROR(e),
+        };
+        let schema = match schema {
+            Value::Null => None,
+            schema => match Schema::compile(schema) {
+                Ok(schema) => Some(schema),
+                Err(e) => return Response::ERROR(e.to_string()),
+            },
+        };
+        match self.kv.set_schema(name, schema) {
+            Ok(()) => Response::OK,
+            Err(e) => Response::ERROR(e.to_string()),
+        }
+    }
+
+    async fn dump(&self, file: String) -> Response {
+        let snapshot = self.kv.snapshot();
+        let path = PathBuf::from(file);
+        match tokio::task::spawn_blocking(move || snapshot.write(&path)).await {
+            Ok(Ok(())) => Response::OK,
+            Ok(Err(e)) => Response::ERROR(format!("dump failed: {}", e)),
+            Err(e) => Response::ERROR(format!("dump failed: {}", e)),
+        }
+    }
+
+    async fn load(&self, file: String, headers: Option<Map<String, Value>>) -> Response {
+        let mode = match headers.as_ref().and_then(|h| h.get("mode")) {
+            None => LoadMode::Replace,
+            Some(Value::String(mode)) if mode.eq_ignore_ascii_case("replace") => LoadMode::Replace,
+            Some(Value::String(mode)) if mode.eq_ignore_ascii_case("merge") => LoadMode::Merge,
+            Some(mode) => return Response::ERROR(format!("invalid load mode {}", mode)),
+        };
+        let path = PathBuf::from(file);
+        let snapshot = match tokio::task::spawn_blocking(move || Snapshot::read(&path)).await {
+            Ok(Ok(snapshot)) => snapshot,
+            Ok(Err(e)) => return Response::ERROR(format!("load failed: {}", e)),
+            Err(e) => return Response::ERROR(format!("load failed: {}", e)),
+        };
+        match self.kv.load(snapshot, mode) {
+            Ok(report) => Response::OBJECT(json!({
+                "collections": report.collections,
+                "objects": report.objects
+            })),
+            Err(e) => Response::ERROR(format!("load failed: {}", e)),
+        }
+    }
+
+    async fn send_response(&self, response: Response) -> Result<(), DSError> {
+        self.tx.send(response).await.map_err(|e| {
             error!("Error forwarding {}", e);
+            DSError::SendError(e)
+        })
+    }
+}
+
+/// Splits `collection/key/path/in/object` into the collection, the key and a JSON Pointer
+/// into the stored value.
+///
+/// The key is escaped like a pointer token (RFC 6901), so `~1` stands for a `/` in it and
+/// `~0` for a `~`. Neither the collection nor the key may be empty.
+fn object_path(uri: &str) -> Result<(&str, String, Pointer), String> {
+    let invalid = || "invalid path".to_string();
+    let (name, rest) = uri.split_once('/').ok_or_else(invalid)?;
+    let (key, pointer) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let key = unescape(key)
+        .filter(|key| !name.is_empty() && !key.is_empty())
+        .ok_or_else(invalid)?;
+    let pointer = Pointer::parse(pointer).map_err(|e| e.to_string())?;
+    Ok((name, key, pointer))
+}
+
+const NOT_BATCHABLE: &str = "this command can't be part of a batch";
+
+/// Whether a command can run inside a batch; ones that hold the whole keyspace themselves,
+/// delete any number of objects, are batches already or control transactions can't.
+fn batchable(command: &Command) -> bool {
+    !matches!(
+        command,
+        Command::DUMP { .. }
+            | Command::LOAD { .. }
+            | Command::REWRITELOG { .. }
+            | Command::DROP { .. }
+            | Command::CLEAR { .. }
+            | Command::PURGE { .. }
+            | Command::RENAME { .. }
+            | Command::INDEX { .. }
+            | Command::DROPINDEX { .. }
+            | Command::SETSCHEMA { .. }
+            | Command::BATCH { .. }
+            | Command::INSERT { .. }
+            | Command::MULTI { .. }
+            | Command::EXEC { .. }
+            | Command::DISCARD { .. }
+            | Command::WATCH { .. }
+    )
+}
+
+/// The object a command changes, if it changes exactly one existing or named object.
+fn written_object(command: &Command) -> Option<(&str, String, Pointer)> {
+    match command {
+        Command::PUT { uri, .. }
+        | Command::PATCH { uri, .. }
+        | Command::DELETE { uri, .. }
+        | Command::EXPIRE { uri, .. }
+        | Command::PERSIST { uri, .. }
+        | Command::INCR { uri, .. }
+        | Command::INCRBY { uri, .. }
+        | Command::PUSH { uri, .. }
+        | Command::POP { uri, .. }
+        | Command::PULL { uri, .. } => object_path(uri).ok(),
+        _ => None,
+    }
+}
+
+/// Checks that a URI names a whole collection.
+fn collection_name(uri: &str) -> Result<&str, String> {
+    if uri.is_empty() || uri.contains('/') {
+        return Err("invalid collection name".to_string());
+    }
+    Ok(uri)
+}
+
+/// Reads the body of INDEX: `{"path": ..., "kind": "hash" | "ordered", "unique": bool}`,
+/// where only the path is required.
+fn index_spec(body: &Value) -> Result<(IndexSpec, Pointer), String> {
+    let Value::Object(body) = body else {
+        return Err("the index must be an object with a path".to_string());
+    };
+    let path = match body.get("path") {
+        Some(Value::String(path)) => field_path(path)?,
+        _ => return Err("the index path must be a string".to_string()),
+    };
+    let kind = match body.get("kind") {
+        None => IndexKind::Hash,
+        Some(Value::String(kind)) if kind.eq_ignore_ascii_case("hash") => IndexKind::Hash,
+        Some(Value::String(kind)) if kind.eq_ignore_ascii_case("ordered") => IndexKind::Ordered,
+        Some(kind) => return Err(format!("invalid index kind {}", kind)),
+    };
+    let unique = match body.get("unique") {
+        None => false,
+        Some(Value::Bool(unique)) => *unique,
+        Some(unique) => return Err(format!("invalid unique flag {}", unique)),
+    };
+    let spec = IndexSpec {
+        path: path.encoded(),
+        kind,
+        unique,
+    };
+    Ok((spec, path))
+}
+
+/// Like [`object_path`], for commands that only apply to whole objects.
+fn object_key(uri: &str) -> Result<(&str, String), String> {
+    match object_path(uri)? {
+        (name, key, pointer) if pointer.is_root() => Ok((name, key)),
+        _ => Err("this command applies to whole objects, not paths inside them".to_string()),
+    }
+}
+
+/// Reads the optional `mode` header of PATCH, which defaults to a merge patch.
+fn patch_mode(headers: &Option<Map<String, Value>>) -> Result<PatchMode, String> {
+    match headers.as_ref().and_then(|h| h.get("mode")) {
+        None => Ok(PatchMode::Merge),
+        Some(Value::String(mode)) if mode.eq_ignore_ascii_case("merge") => Ok(PatchMode::Merge),
+        Some(Value::String(mode)) if mode.eq_ignore_ascii_case("json-patch") => {
+            Ok(PatchMode::JsonPatch)
         }
+        Some(Value::String(mode)) if mode.eq_ignore_ascii_case("append") => Ok(PatchMode::Append),
+        Some(mode) => Err(format!("invalid patch mode {}", mode)),
+    }
+}
+
+/// The reply to a write at `pointer`: the object's key and new version along with
+/// `fields`, which say what the write did.
+fn written(id: &str, pointer: &Pointer, version: u64, fields: Value) -> Response {
+    let mut reply = json!({ "ID": id, "version": version });
+    if !pointer.is_root() {
+        reply["path"] = json!(pointer.to_string());
+    }
+    if let (Value::Object(reply), Value::Object(fields)) = (&mut reply, fields) {
+        reply.extend(fields);
+    }
+    Response::OBJECT(reply)
+}
+
+/// Reads the `at` header of PUSH and POP: `"front"`, `"back"` (the default) or an index.
+fn position_header(headers: &Option<Map<String, Value>>) -> Result<Position, String> {
+    let at = match headers.as_ref().and_then(|h| h.get("at")) {
+        None => return Ok(Position::Back),
+        Some(at) => at,
+    };
+    match at {
+        Value::String(end) if end.eq_ignore_ascii_case("front") => Some(Position::Front),
+        Value::String(end) if end.eq_ignore_ascii_case("back") => Some(Position::Back),
+        Value::Number(index) => index.as_u64().map(|i| Position::Index(i as usize)),
+        _ => None,
+    }
+    .ok_or_else(|| format!("invalid at header {}", at))
+}
+
+/// Where in an array PUSH and POP work.
+#[derive(Debug, Clone, Copy)]
+enum Position {
+    Front,
+    Back,
+    Index(usize),
+}
+
+/// Adds two JSON numbers, staying in integers while both are integers. Sums of integers
+/// that fit neither an `i64` nor a `u64` overflow rather than being rounded.
+fn add(a: &Number, b: &Number) -> Result<Number, StoreError> {
+    let integer = |n: &Number| n.as_i64().map(i128::from).or(n.as_u64().map(i128::from));
+    if let (Some(a), Some(b)) = (integer(a), integer(b)) {
+        let sum = a + b;
+        return i64::try_from(sum)
+            .map(Number::from)
+            .or_else(|_| u64::try_from(sum).map(Number::from))
+            .map_err(|_| StoreError::Overflow);
+    }
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => Number::from_f64(a + b).ok_or(StoreError::Overflow),
+        _ => Err(StoreError::Overflow),
+    }
+}
+
+/// The reply item for one object of a collection read.
+fn envelope(id: &str, object: &Object, projection: &Projection) -> Value {
+    json!({
+        "ID": id,
+        "value": projection.apply(&object.value),
+        "version": object.version
+    })
+}
+
+/// Sorts and projects a value read from an object; only arrays can be sorted.
+fn shape(mut value: Value, sort: Option<&Sort>, projection: &Projection) -> Result<Value, String> {
+    if let Some(sort) = sort {
+        let Value::Array(values) = &mut value else {
+            return Err("only arrays can be sorted".to_string());
+        };
+        sort.sort(values);
+    }
+    Ok(match projection {
+        Projection::All => value,
+        projection => projection.apply(&value),
+    })
+}
+
+/// Reads the `order`, `limit` and `cursor` headers of a collection read.
+fn range_headers(headers: &Option<Map<String, Value>>) -> Result<Range, String> {
+    let header = |name| headers.as_ref().and_then(|h| h.get(name));
+    let descending = match header("order") {
+        None => false,
+        Some(Value::String(order)) if order.eq_ignore_ascii_case("asc") => false,
+        Some(Value::String(order)) if order.eq_ignore_ascii_case("desc") => true,
+        Some(order) => return Err(format!("invalid order header {}", order)),
+    };
+    let limit = match header("limit") {
+        None => None,
+        Some(limit) => match limit.as_u64() {
+            Some(limit) if limit > 0 => Some(limit as usize),
+            _ => return Err(format!("invalid limit header {}", limit)),
+        },
+    };
+    let cursor = match header("cursor") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(cursor)) => {
+            Some(decode_cursor(cursor).ok_or_else(|| "invalid cursor".to_string())?)
+        }
+        Some(cursor) => return Err(format!("invalid cursor header {}", cursor)),
+    };
+    Ok(Range {
+        cursor,
+        descending,
+        limit,
+        created_after: time_header(headers, "created_after")?,
+        created_before: time_header(headers, "created_before")?,
+    })
+}
+
+/// Reads just the creation time bounds of a read, for reads that don't page.
+fn created_range(headers: &Option<Map<String, Value>>) -> Result<Range, String> {
+    Ok(Range {
+        created_after: time_header(headers, "created_after")?,
+        created_before: time_header(headers, "created_before")?,
+        ..Range::default()
+    })
+}
+
+/// Reads a header holding a Unix time in milliseconds.
+fn time_header(headers: &Option<Map<String, Value>>, name: &str) -> Result<Option<u64>, String> {
+    match headers.as_ref().and_then(|h| h.get(name)) {
+        None | Some(Value::Null) => Ok(None),
+        Some(time) => time
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("invalid {} header {}", name, time)),
+    }
+}
+
+/// Reads the cutoff of PURGE: a `created_before` time in milliseconds, or an `older_than`
+/// age in seconds.
+fn purge_cutoff(headers: &Option<Map<String, Value>>) -> Result<u64, String> {
+    let before = time_header(headers, "created_before")?;
+    let older_than = match headers.as_ref().and_then(|h| h.get("older_than")) {
+        None | Some(Value::Null) => None,
+        Some(age) => match age.as_f64().filter(|s| *s >= 0.0 && s.is_finite()) {
+            Some(seconds) => Some(now_millis().saturating_sub((seconds * 1000.0) as u64)),
+            None => return Err(format!("invalid older_than header {}", age)),
+        },
+    };
+    match (before, older_than) {
+        (Some(cutoff), None) | (None, Some(cutoff)) => Ok(cutoff),
+        _ => Err("PURGE needs either a created_before or an older_than header".to_string()),
+    }
+}
+
+/// Turns the key a page ended at into the cursor handed to clients.
+fn encode_cursor(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Option<String> {
+    if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+        return None;
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Reads the optional `if-match` header: the version a write expects the object to be at.
+fn if_match_header(headers: &Option<Map<String, Value>>) -> Result<Option<u64>, String> {
+    let version = match headers.as_ref().and_then(|h| h.get("if-match")) {
+        None => return Ok(None),
+        Some(Value::Number(version)) => version.as_u64(),
+        Some(Value::String(version)) => version.parse().ok(),
+        Some(_) => None,
+    };
+    version
+        .map(Some)
+        .ok_or_else(|| "invalid if-match header".to_string())
+}
+
+/// Reads the optional `ttl` header, given in seconds, as an absolute expiry time.
+fn ttl_header(headers: &Option<Map<String, Value>>) -> Result<Option<u64>, String> {
+    match headers.as_ref().and_then(|h| h.get("ttl")) {
+        None => Ok(None),
+        Some(ttl) => expires_at(ttl)
+            .map(Some)
+            .ok_or_else(|| "invalid ttl".to_string()),
+    }
+}
+
+/// Turns a time-to-live in seconds into a unix time in milliseconds.
+fn expires_at(ttl: &Value) -> Option<u64> {
+    let seconds = ttl.as_f64().filter(|s| *s > 0.0 && s.is_finite())?;
+    Some(now_millis().saturating_add((seconds * 1000.0).ceil() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn data_store() -> DataStore {
+        let (tx, _) = mpsc::channel(1);
+        let (_, rx) = mpsc::channel(1);
+        DataStore::new(Arc::new(Keyspace::new()), tx, rx)
+    }
+
+    fn put(uri: &str) -> Command {
+        Command::PUT {
+            uri: uri.to_string(),
+            body: json!({"n": 1}),
+            headers: None,
+        }
+    }
+
+    #[test]
+    fn batches_refuse_transaction_commands() {
+        let store = data_store();
+        let mut atomic = Map::new();
+        atomic.insert("atomic".to_string(), json!(true));
+        let controls = [
+            Command::MULTI { headers: None },
+            Command::EXEC { headers: None },
+            Command::DISCARD { headers: None },
+            Command::WATCH {
+                uri: "c/b".to_string(),
+                headers: None,
+            },
+        ];
+        for control in controls {
+            let batch = vec![put("c/a"), control, put("c/b")];
+            let response = store.batch(batch, &Some(atomic.clone()));
+            assert!(matches!(response, Response::ERROR(_)), "{:?}", response);
+            assert!(!store.kv.has_collection("c"));
+        }
+        let response = store.batch(vec![Command::MULTI { headers: None }], &None);
+        assert!(matches!(&response, Response::BATCH(r) if matches!(r[..], [Response::ERROR(_)])));
+        let response = store.apply(Command::EXEC { headers: None });
+        assert!(matches!(response, Response::ERROR(_)));
+    }
+
+    #[test]
+    fn integer_sums_stay_exact_or_overflow() {
+        let add = |a: Value, b: Value| {
+            let (Value::Number(a), Value::Number(b)) = (a, b) else {
+                unreachable!()
+            };
+            add(&a, &b).map(Value::Number)
+        };
+        assert_eq!(add(json!(1), json!(-2)).unwrap(), json!(-1));
+        assert_eq!(
+            add(json!(i64::MAX), json!(1)).unwrap(),
+            json!(i64::MAX as u64 + 1)
+        );
+        assert_eq!(add(json!(u64::MAX - 1), json!(1)).unwrap(), json!(u64::MAX));
+        assert_eq!(
+            add(json!(u64::MAX), json!(-1)).unwrap(),
+            json!(u64::MAX - 1)
+        );
+        assert_eq!(
+            add(json!(i64::MIN), json!(u64::MAX)).unwrap(),
+            json!(i64::MAX as u64)
+        );
+        assert!(matches!(
+            add(json!(u64::MAX), json!(1)),
+            Err(StoreError::Overflow)
+        ));
+        assert!(matches!(
+            add(json!(i64::MIN), json!(-1)),
+            Err(StoreError::Overflow)
+        ));
+        assert_eq!(add(json!(1.5), json!(1)).unwrap(), json!(2.5));
     }
 }
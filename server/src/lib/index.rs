@@ -0,0 +1,452 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+
+use crate::pointer::Pointer;
+use crate::query::{Condition, Filter};
+
+/// How an index arranges its keys, which decides the conditions it can answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// Finds equal values only.
+    Hash,
+    /// Keeps values sorted, so it also answers `$gt`, `$gte`, `$lt` and `$lte`.
+    Ordered,
+}
+
+/// What a client declares an index with; it is logged and snapshotted as is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexSpec {
+    /// The indexed field as a JSON Pointer into each object.
+    pub path: String,
+    pub kind: IndexKind,
+    /// No two objects may hold the same value at `path`.
+    #[serde(default)]
+    pub unique: bool,
+}
+
+/// What the index listing reports about one index.
+#[derive(Debug, Clone)]
+pub struct IndexInfo {
+    pub spec: IndexSpec,
+    /// Distinct values held.
+    pub keys: usize,
+}
+
+/// A secondary index from the values at one path to the keys of the objects holding them.
+///
+/// Only `null`, booleans, numbers and strings are indexed; objects whose field is missing
+/// or holds an array or object are left out, so queries about those fall back to a scan.
+pub(crate) struct Index {
+    pub spec: IndexSpec,
+    path: Pointer,
+    entries: Entries,
+}
+
+enum Entries {
+    Hash(HashMap<IndexKey, BTreeSet<String>>),
+    Ordered(BTreeMap<IndexKey, BTreeSet<String>>),
+}
+
+impl Index {
+    pub fn new(spec: IndexSpec, path: Pointer) -> Self {
+        let entries = match spec.kind {
+            IndexKind::Hash => Entries::Hash(HashMap::new()),
+            IndexKind::Ordered => Entries::Ordered(BTreeMap::new()),
+        };
+        Self {
+            spec,
+            path,
+            entries,
+        }
+    }
+
+    pub fn path(&self) -> &Pointer {
+        &self.path
+    }
+
+    pub fn info(&self) -> IndexInfo {
+        IndexInfo {
+            spec: self.spec.clone(),
+            keys: match &self.entries {
+                Entries::Hash(entries) => entries.len(),
+                Entries::Ordered(entries) => entries.len(),
+            },
+        }
+    }
+
+    fn key(&self, value: &Value) -> Option<IndexKey> {
+        IndexKey::new(self.path.get(value)?)
+    }
+
+    fn ids(&self, key: &IndexKey) -> Option<&BTreeSet<String>> {
+        match &self.entries {
+            Entries::Hash(entries) => entries.get(key),
+            Entries::Ordered(entries) => entries.get(key),
+        }
+    }
+
+    /// Whether storing `value` under `id` would break uniqueness.
+    pub fn conflicts(&self, id: &str, value: &Value) -> bool {
+        self.spec.unique
+            && self
+                .key(value)
+                .and_then(|key| self.ids(&key))
+                .is_some_and(|ids| ids.iter().any(|other| other != id))
+    }
+
+    /// Whether a unique index holds some value for more than one object, as it can when
+    /// it is built over existing objects.
+    pub fn has_duplicates(&self) -> bool {
+        self.spec.unique
+            && match &self.entries {
+                Entries::Hash(entries) => entries.values().any(|ids| ids.len() > 1),
+                Entries::Ordered(entries) => entries.values().any(|ids| ids.len() > 1),
+            }
+    }
+
+    pub fn insert(&mut self, id: &str, value: &Value) {
+        let Some(key) = self.key(value) else {
+            return;
+        };
+        let ids = match &mut self.entries {
+            Entries::Hash(entries) => entries.entry(key).or_default(),
+            Entries::Ordered(entries) => entries.entry(key).or_default(),
+        };
+        ids.insert(id.to_string());
+    }
+
+    pub fn remove(&mut self, id: &str, value: &Value) {
+        let Some(key) = self.key(value) else {
+            return;
+        };
+        let emptied = match &mut self.entries {
+            Entries::Hash(entries) => entries.get_mut(&key).is_some_and(|ids| {
+                ids.remove(id);
+                ids.is_empty()
+            }),
+            Entries::Ordered(entries) => entries.get_mut(&key).is_some_and(|ids| {
+                ids.remove(id);
+                ids.is_empty()
+            }),
+        };
+        if emptied {
+            match &mut self.entries {
+                Entries::Hash(entries) => entries.remove(&key),
+                Entries::Ordered(entries) => entries.remove(&key),
+            };
+        }
+    }
+
+    /// The keys of every object that may meet `condition`, or `None` if this index can't
+    /// tell.
+    fn lookup(&self, condition: &Condition) -> Option<BTreeSet<String>> {
+        let equal = |operand: &Value| match operand {
+            // A missing field equals `null` too, and missing fields aren't indexed.
+            Value::Null => None,
+            // Arrays and objects aren't indexed either.
+            operand => Some(
+                self.ids(&IndexKey::new(operand)?)
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+        };
+        match condition {
+            Condition::Eq(operand) => equal(operand),
+            Condition::In(operands) => operands.iter().try_fold(BTreeSet::new(), |mut ids, o| {
+                ids.extend(equal(o)?);
+                Some(ids)
+            }),
+            Condition::Gt(operand) => self.range(operand, Ordering::Greater, false),
+            Condition::Gte(operand) => self.range(operand, Ordering::Greater, true),
+            Condition::Lt(operand) => self.range(operand, Ordering::Less, false),
+            Condition::Lte(operand) => self.range(operand, Ordering::Less, true),
+            _ => None,
+        }
+    }
+
+    /// The keys of objects whose value lies on the `side` of `operand`, among values of
+    /// the same type, since only numbers with numbers and strings with strings compare.
+    fn range(&self, operand: &Value, side: Ordering, inclusive: bool) -> Option<BTreeSet<String>> {
+        let Entries::Ordered(entries) = &self.entries else {
+            return None;
+        };
+        let operand = IndexKey::new(operand)?;
+        let (first, past) = match operand {
+            IndexKey::Number(_) => (
+                Bound::Excluded(IndexKey::Bool(true)),
+                Bound::Excluded(IndexKey::String(String::new())),
+            ),
+            IndexKey::String(_) => (
+                Bound::Included(IndexKey::String(String::new())),
+                Bound::Unbounded,
+            ),
+            _ => return None,
+        };
+        let at = if inclusive {
+            Bound::Included(operand)
+        } else {
+            Bound::Excluded(operand)
+        };
+        let bounds = match side {
+            Ordering::Greater => (at, past),
+            _ => (first, at),
+        };
+        Some(
+            entries
+                .range(bounds)
+                .flat_map(|(_, ids)| ids)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// The keys of every object that may match `filter`, found through `indexes`, or `None`
+/// when the filter needs a full scan. Callers still check each object against the filter.
+pub(crate) fn candidates(indexes: &[Index], filter: &Filter) -> Option<BTreeSet<String>> {
+    match filter {
+        Filter::Field { path, condition } => indexes
+            .iter()
+            .filter(|index| index.path() == path)
+            .find_map(|index| index.lookup(condition)),
+        Filter::And(filters) => filters
+            .iter()
+            .filter_map(|filter| candidates(indexes, filter))
+            .reduce(|a, b| a.intersection(&b).cloned().collect()),
+        Filter::Or(filters) => filters.iter().try_fold(BTreeSet::new(), |mut ids, filter| {
+            ids.extend(candidates(indexes, filter)?);
+            Some(ids)
+        }),
+        Filter::All | Filter::Not(_) => None,
+    }
+}
+
+/// An indexed value. Variants sort in the same order values of different types sort in
+/// reads.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum IndexKey {
+    Null,
+    Bool(bool),
+    Number(NumberKey),
+    String(String),
+}
+
+impl IndexKey {
+    fn new(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::Null => IndexKey::Null,
+            Value::Bool(b) => IndexKey::Bool(*b),
+            Value::Number(n) => IndexKey::Number(NumberKey::new(n)),
+            Value::String(s) => IndexKey::String(s.clone()),
+            Value::Array(_) | Value::Object(_) => return None,
+        })
+    }
+}
+
+/// A number, with integral floats stored as integers so that `1` and `1.0` are one key.
+#[derive(Debug, Clone, Copy)]
+enum NumberKey {
+    Int(i64),
+    /// Only used above `i64::MAX`.
+    UInt(u64),
+    Float(f64),
+}
+
+impl NumberKey {
+    fn new(n: &Number) -> Self {
+        if let Some(i) = n.as_i64() {
+            return NumberKey::Int(i);
+        }
+        if let Some(u) = n.as_u64() {
+            return NumberKey::UInt(u);
+        }
+        let f = n.as_f64().unwrap_or_default();
+        if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 {
+            NumberKey::Int(f as i64)
+        } else if f.fract() == 0.0 && f >= 0.0 && f < u64::MAX as f64 {
+            NumberKey::UInt(f as u64)
+        } else {
+            NumberKey::Float(f)
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            NumberKey::Int(i) => i as f64,
+            NumberKey::UInt(u) => u as f64,
+            NumberKey::Float(f) => f,
+        }
+    }
+}
+
+impl Ord for NumberKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (NumberKey::Int(a), NumberKey::Int(b)) => a.cmp(b),
+            (NumberKey::UInt(a), NumberKey::UInt(b)) => a.cmp(b),
+            (NumberKey::Float(a), NumberKey::Float(b)) => a.total_cmp(b),
+            (NumberKey::Int(_), NumberKey::UInt(_)) => Ordering::Less,
+            (NumberKey::UInt(_), NumberKey::Int(_)) => Ordering::Greater,
+            // Floats left as floats aren't whole or lie outside the integer ranges, so
+            // they never equal an integer.
+            (a, b) => a.as_f64().total_cmp(&b.as_f64()),
+        }
+    }
+}
+
+impl PartialOrd for NumberKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for NumberKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for NumberKey {}
+
+impl Hash for NumberKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            NumberKey::Int(i) => (0u8, i).hash(state),
+            NumberKey::UInt(u) => (1u8, u).hash(state),
+            NumberKey::Float(f) => (2u8, f.to_bits()).hash(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map};
+
+    use super::*;
+
+    fn index(path: &str, kind: IndexKind, unique: bool) -> Index {
+        let spec = IndexSpec {
+            path: path.to_string(),
+            kind,
+            unique,
+        };
+        Index::new(spec, Pointer::parse(path).unwrap())
+    }
+
+    fn filter(query: Value) -> Filter {
+        let mut headers = Map::new();
+        headers.insert("where".to_string(), query);
+        Filter::from_headers(&Some(headers)).unwrap()
+    }
+
+    fn objects() -> Vec<(String, Value)> {
+        [
+            json!({"n": 1, "s": "a", "tags": [1, 2]}),
+            json!({"n": 1.0, "s": "b"}),
+            json!({"n": 2.5, "s": "c", "tags": [1, 2]}),
+            json!({"n": -3, "s": "a", "tags": {"x": 1}}),
+            json!({"n": "2", "s": null}),
+            json!({"n": null}),
+            json!({"n": true}),
+            json!({}),
+            json!({"n": 18446744073709551615u64, "s": "z"}),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| (format!("k{}", i), value))
+        .collect()
+    }
+
+    /// Every filter must find the same objects through the indexes as by a full scan.
+    #[test]
+    fn indexed_reads_match_a_scan() {
+        let objects = objects();
+        let mut indexes = vec![
+            index("/n", IndexKind::Ordered, false),
+            index("/s", IndexKind::Hash, false),
+            index("/tags", IndexKind::Hash, false),
+        ];
+        for index in &mut indexes {
+            for (id, value) in &objects {
+                index.insert(id, value);
+            }
+        }
+        let queries = [
+            json!({"n": 1}),
+            json!({"n": 1.0}),
+            json!({"n": {"$gt": 1}}),
+            json!({"n": {"$gte": 1, "$lt": 3}}),
+            json!({"n": {"$lte": 0}}),
+            json!({"n": {"$gt": "1"}}),
+            json!({"n": {"$in": [2.5, "2", true]}}),
+            json!({"n": null}),
+            json!({"n": {"$ne": 1}}),
+            json!({"n": 18446744073709551615u64}),
+            json!({"s": "a"}),
+            json!({"s": {"$in": ["a", "c"]}}),
+            json!({"s": {"$gt": "a"}}),
+            json!({"tags": [1, 2]}),
+            json!({"tags": {"x": 1}}),
+            json!({"tags": {"$in": [[1, 2]]}}),
+            json!({"n": 1, "s": "b"}),
+            json!({"$or": [{"n": 1}, {"s": "c"}]}),
+            json!({"$or": [{"n": 1}, {"missing": 1}]}),
+            json!({"$not": {"n": 1}}),
+        ];
+        for query in queries {
+            let filter = filter(query.clone());
+            let scanned: BTreeSet<_> = objects
+                .iter()
+                .filter(|(_, value)| filter.matches(value))
+                .map(|(id, _)| id.clone())
+                .collect();
+            let Some(candidates) = candidates(&indexes, &filter) else {
+                continue;
+            };
+            let found: BTreeSet<_> = objects
+                .iter()
+                .filter(|(id, value)| candidates.contains(id) && filter.matches(value))
+                .map(|(id, _)| id.clone())
+                .collect();
+            assert_eq!(found, scanned, "{}", query);
+        }
+    }
+
+    #[test]
+    fn array_and_object_operands_fall_back_to_a_scan() {
+        let indexes = [index("/tags", IndexKind::Hash, false)];
+        assert!(candidates(&indexes, &filter(json!({"tags": [1, 2]}))).is_none());
+        assert!(candidates(&indexes, &filter(json!({"tags": {"$in": [[1]]}}))).is_none());
+        assert!(candidates(&indexes, &filter(json!({"tags": null}))).is_none());
+        assert!(candidates(&indexes, &filter(json!({"tags": 1}))).is_some());
+    }
+
+    #[test]
+    fn hash_indexes_only_answer_equality() {
+        let indexes = [index("/n", IndexKind::Hash, false)];
+        assert!(candidates(&indexes, &filter(json!({"n": {"$gt": 1}}))).is_none());
+        assert!(candidates(&indexes, &filter(json!({"n": 1}))).is_some());
+    }
+
+    #[test]
+    fn unique_indexes_spot_conflicts() {
+        let mut index = index("/e", IndexKind::Hash, true);
+        index.insert("a", &json!({"e": 1}));
+        assert!(index.conflicts("b", &json!({"e": 1.0})));
+        assert!(!index.conflicts("a", &json!({"e": 1})));
+        assert!(!index.conflicts("b", &json!({"e": 2})));
+        assert!(!index.has_duplicates());
+        index.insert("b", &json!({"e": 1}));
+        assert!(index.has_duplicates());
+        index.remove("b", &json!({"e": 1}));
+        assert!(!index.has_duplicates());
+        index.remove("a", &json!({"e": 1}));
+        assert_eq!(index.info().keys, 0);
+    }
+}
@@ -0,0 +1,2043 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::ops::{Bound, Deref};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{
+    Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+
+use dashmap::mapref::entry::OccupiedEntry;
+use dashmap::{mapref::one::Ref, DashMap, Entry};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tracing::{error, info};
+use ulid::Ulid;
+
+use crate::aof::{unix_millis, Aof, AofError, FsyncPolicy, LogConfig, LogRecord, ReplayReport};
+use crate::index::{self, Index, IndexInfo, IndexSpec};
+use crate::memory::{self, EvictionPolicy, MemoryConfig};
+use crate::patch::PatchError;
+use crate::pointer::{Pointer, PointerError};
+use crate::query::Filter;
+use crate::schema::{Schema, ValidationReport, Violations};
+use crate::snapshot::{CollectionDump, ObjectDump, Snapshot, SnapshotError};
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("collection not found")]
+    CollectionNotFound,
+
+    #[error("collection already exists")]
+    CollectionExists,
+
+    #[error("object not found")]
+    ObjectNotFound,
+
+    #[error("object already exists")]
+    AlreadyExists,
+
+    #[error("the unique index on {0} already holds this value")]
+    Duplicate(String),
+
+    #[error("index already exists")]
+    IndexExists,
+
+    #[error("index not found")]
+    IndexNotFound,
+
+    #[error("the value does not match the collection schema: {0}")]
+    SchemaViolation(Violations),
+
+    #[error("the collection has no schema")]
+    NoSchema,
+
+    #[error("version conflict: the object is not at version {0}")]
+    VersionConflict(u64),
+
+    #[error("type mismatch")]
+    TypeMismatch,
+
+    #[error("the value at {0} is not a number")]
+    NotANumber(Pointer),
+
+    #[error("the result does not fit in a number")]
+    Overflow,
+
+    #[error("the value at {0} is not an array")]
+    NotAnArray(Pointer),
+
+    #[error("index {0} is out of range")]
+    IndexOutOfRange(usize),
+
+    #[error("the array is empty")]
+    EmptyArray,
+
+    #[error("{0}")]
+    Path(#[from] PointerError),
+
+    #[error("{0}")]
+    Patch(#[from] PatchError),
+
+    #[error("out of memory: the write would exceed max-memory")]
+    OutOfMemory,
+
+    #[error("append-only log write failed: {0}")]
+    Log(#[from] AofError),
+}
+
+/// How a loaded snapshot is combined with the live keyspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadMode {
+    /// Drop everything in memory and keep only what the snapshot holds.
+    Replace,
+    /// Keep existing objects; objects from the snapshot win on id clashes.
+    Merge,
+}
+
+/// What a LOAD put into the keyspace.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LoadReport {
+    pub collections: usize,
+    pub objects: usize,
+}
+
+/// What the collection listing reports about one collection.
+#[derive(Debug, Clone)]
+pub struct CollectionInfo {
+    pub name: String,
+    /// Live objects, not counting expired ones that haven't been swept yet.
+    pub objects: usize,
+    /// Approximate bytes the live objects take up.
+    pub bytes: usize,
+}
+
+/// A stored value and the bookkeeping the keyspace keeps for it.
+#[derive(Debug)]
+pub struct Object {
+    pub value: Value,
+    /// Unix time in milliseconds from which the object no longer exists.
+    pub expires_at: Option<u64>,
+    /// Taken from a keyspace-wide counter on every write, so it never repeats for a key,
+    /// even after the object is deleted and created again.
+    pub version: u64,
+    /// Approximate bytes the object takes up, counted against the memory limit.
+    size: usize,
+    /// Unix time in milliseconds of the last read or write, for LRU eviction.
+    last_access: AtomicU64,
+    /// Reads and writes so far, for LFU eviction.
+    hits: AtomicU64,
+}
+
+impl Object {
+    pub fn new(value: Value, expires_at: Option<u64>) -> Self {
+        Self {
+            size: memory::object_size(&value),
+            value,
+            expires_at,
+            // Assigned by the keyspace when the object is stored.
+            version: 0,
+            last_access: AtomicU64::new(now_millis()),
+            hits: AtomicU64::new(1),
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    fn touch(&self) {
+        self.last_access.store(now_millis(), Ordering::Relaxed);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Object {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            expires_at: self.expires_at,
+            version: self.version,
+            size: self.size,
+            last_access: AtomicU64::new(self.last_access.load(Ordering::Relaxed)),
+            hits: AtomicU64::new(self.hits.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// The objects of one collection, their keys in order for ordered reads, and the indexes
+/// and schema declared on it.
+#[derive(Default)]
+pub(crate) struct Collection {
+    objects: DashMap<String, Object>,
+    /// Kept in step with `objects` by [`Keyspace::replaced`]. Keys made by POST are ULIDs,
+    /// so this is also creation order for them.
+    order: RwLock<BTreeSet<String>>,
+    /// Also kept in step by [`Keyspace::replaced`]; declared and dropped only while the
+    /// keyspace is held exclusively.
+    indexes: RwLock<Vec<Index>>,
+    /// Held by writers from checking unique indexes until the object is stored, so two
+    /// writers can't both claim the same value. Always taken after the object's entry.
+    unique_writes: Mutex<()>,
+    /// Every object written must match this; set only while the keyspace is held
+    /// exclusively.
+    schema: RwLock<Option<Arc<Schema>>>,
+}
+
+impl Collection {
+    /// Up to `n` keys within `bounds` that come after `cursor` when walking in the given
+    /// direction.
+    fn keys_after(
+        &self,
+        cursor: Option<&str>,
+        descending: bool,
+        bounds: (Bound<&str>, Bound<&str>),
+        n: usize,
+    ) -> Vec<String> {
+        let order = self.order.read().unwrap_or_else(PoisonError::into_inner);
+        keys_after(&order, cursor, descending, bounds, n)
+    }
+
+    fn indexes(&self) -> RwLockReadGuard<'_, Vec<Index>> {
+        self.indexes.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Keys of the objects that may match `filter`, if an index narrows them down.
+    fn candidates(&self, filter: &Filter) -> Option<BTreeSet<String>> {
+        index::candidates(&self.indexes(), filter)
+    }
+
+    /// Serializes writers while the collection has a unique index.
+    fn lock_unique(&self) -> Option<MutexGuard<'_, ()>> {
+        if !self.indexes().iter().any(|index| index.spec.unique) {
+            return None;
+        }
+        Some(
+            self.unique_writes
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+
+    /// Fails if storing `value` under `id` would put a duplicate into a unique index.
+    fn check_unique(&self, id: &str, value: &Value) -> Result<(), StoreError> {
+        match self
+            .indexes()
+            .iter()
+            .find(|index| index.conflicts(id, value))
+        {
+            Some(index) => Err(StoreError::Duplicate(index.spec.path.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn schema(&self) -> Option<Arc<Schema>> {
+        self.schema
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set_schema(&self, schema: Option<Schema>) {
+        *self.schema.write().unwrap_or_else(PoisonError::into_inner) = schema.map(Arc::new);
+    }
+
+    /// Fails with every violation if the collection has a schema `value` doesn't match.
+    fn check_schema(&self, value: &Value) -> Result<(), StoreError> {
+        let Some(schema) = self.schema() else {
+            return Ok(());
+        };
+        let violations = schema.validate(value);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(StoreError::SchemaViolation(Violations(violations)))
+        }
+    }
+
+    fn reindex(&self, id: &str, old: Option<&Value>, new: Option<&Value>) {
+        if self.indexes().is_empty() {
+            return;
+        }
+        let mut indexes = self.indexes.write().unwrap_or_else(PoisonError::into_inner);
+        for index in indexes.iter_mut() {
+            if let Some(old) = old {
+                index.remove(id, old);
+            }
+            if let Some(new) = new {
+                index.insert(id, new);
+            }
+        }
+    }
+
+    /// A new index over the objects currently in the collection.
+    fn build_index(&self, spec: IndexSpec, path: Pointer) -> Index {
+        let mut index = Index::new(spec, path);
+        for object in self.objects.iter() {
+            index.insert(object.key(), &object.value);
+        }
+        index
+    }
+
+    fn index_specs(&self) -> Vec<IndexSpec> {
+        self.indexes()
+            .iter()
+            .map(|index| index.spec.clone())
+            .collect()
+    }
+}
+
+/// Up to `n` of `keys` that come after `cursor` when walking in the given direction.
+fn keys_after(
+    keys: &BTreeSet<String>,
+    cursor: Option<&str>,
+    descending: bool,
+    (mut low, mut high): (Bound<&str>, Bound<&str>),
+    n: usize,
+) -> Vec<String> {
+    // The cursor replaces the bound it walks away from unless that bound is tighter.
+    match (cursor, descending) {
+        (Some(cursor), false) if bound_key(low).is_none_or(|low| cursor >= low) => {
+            low = Bound::Excluded(cursor)
+        }
+        (Some(cursor), true) if bound_key(high).is_none_or(|high| cursor <= high) => {
+            high = Bound::Excluded(cursor)
+        }
+        _ => {}
+    }
+    // `BTreeSet::range` panics on bounds that cross.
+    let empty = match (low, high) {
+        (Bound::Included(l), Bound::Included(h)) => l > h,
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Included(h) | Bound::Excluded(h)) => {
+            l >= h
+        }
+        _ => false,
+    };
+    if empty {
+        return Vec::new();
+    }
+    let range = keys.range::<str, _>((low, high));
+    if descending {
+        range.rev().take(n).cloned().collect()
+    } else {
+        range.take(n).cloned().collect()
+    }
+}
+
+fn bound_key(bound: Bound<&str>) -> Option<&str> {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) => Some(key),
+        Bound::Unbounded => None,
+    }
+}
+
+impl Deref for Collection {
+    type Target = DashMap<String, Object>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.objects
+    }
+}
+
+/// Where a collection read starts and how much it returns.
+#[derive(Debug, Clone, Default)]
+pub struct Range {
+    /// The key the previous page ended at; this page starts just past it.
+    pub cursor: Option<String>,
+    /// Walk from the highest key down instead of from the lowest up.
+    pub descending: bool,
+    pub limit: Option<usize>,
+    /// Only objects whose ULID key was made after this Unix time in milliseconds.
+    pub created_after: Option<u64>,
+    /// Only objects whose ULID key was made before this Unix time in milliseconds.
+    pub created_before: Option<u64>,
+}
+
+impl Range {
+    /// The keys between which every ULID made inside the creation time bounds lies, or
+    /// `None` if the read isn't limited by creation time.
+    fn created(&self) -> Option<(Bound<String>, Bound<String>)> {
+        if self.created_after.is_none() && self.created_before.is_none() {
+            return None;
+        }
+        let low = self.created_after.map_or(Bound::Unbounded, |ms| {
+            Bound::Included(first_ulid(ms.saturating_add(1)))
+        });
+        let high = self
+            .created_before
+            .map_or(Bound::Unbounded, |ms| Bound::Excluded(first_ulid(ms)));
+        Some((low, high))
+    }
+}
+
+/// The lowest ULID that can be made at `ms`. ULIDs sort by the time they were made, since
+/// it leads them and their characters sort in the order of their values.
+fn first_ulid(ms: u64) -> String {
+    if ms > ULID_MAX_TIME {
+        // Above every ULID, all of which start with a digit up to 7.
+        return "8".to_string();
+    }
+    Ulid::from_parts(ms, 0).to_string()
+}
+
+/// The last millisecond the 48-bit time of a ULID can hold.
+const ULID_MAX_TIME: u64 = (1 << 48) - 1;
+
+/// Whether `key` is a ULID written the way POST makes them.
+fn is_ulid(key: &str) -> bool {
+    key.len() == ulid::ULID_LEN && Ulid::from_string(key).is_ok_and(|ulid| ulid.to_string() == key)
+}
+
+/// What was read from a collection, in key order.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Where the next page starts, if there are keys left to look at.
+    pub next: Option<String>,
+}
+
+/// How to put back what a group of commands changed, for groups that must take effect
+/// all together or not at all.
+#[derive(Default)]
+pub(crate) struct Undo {
+    /// Each object the group changed, as it was before its first change.
+    objects: Vec<(String, String, Option<Object>)>,
+    saved: HashSet<(String, String)>,
+    /// Collections the group created.
+    collections: Vec<String>,
+}
+
+impl Undo {
+    /// Notes an object the group has just created under a fresh key.
+    pub fn created(&mut self, name: &str, id: &str) {
+        self.saved.insert((name.to_string(), id.to_string()));
+        self.objects.push((name.to_string(), id.to_string(), None));
+    }
+}
+
+/// Every collection known to the server, shared by all connections.
+#[derive(Default)]
+pub struct Keyspace {
+    pub(crate) collections: DashMap<String, Collection>,
+    /// Commands hold this shared; operations that need the whole keyspace to stand still
+    /// (such as taking a snapshot) hold it exclusively.
+    gate: RwLock<()>,
+    /// Set once at startup, after the existing log has been replayed.
+    log: OnceLock<Aof>,
+    /// Objects with an expiry time, soonest first, for the background sweeper.
+    expiries: Mutex<BTreeSet<(u64, String, String)>>,
+    memory: MemoryConfig,
+    /// Sum of the sizes of all stored objects.
+    used_memory: AtomicUsize,
+    evicted: AtomicU64,
+    /// The last object version handed out.
+    versions: AtomicU64,
+    /// Held while evicting so concurrent writers don't each look for victims.
+    evicting: Mutex<EvictionHand>,
+    /// Set while a group of commands holds the keyspace through [`Keyspace::exclusive`].
+    eviction_paused: AtomicBool,
+}
+
+/// Where eviction picks up sampling: the collection and key it looked at last, so that
+/// successive samples sweep through every object instead of seeing the same ones again.
+#[derive(Default)]
+struct EvictionHand {
+    collection: String,
+    key: String,
+}
+
+/// How many objects eviction compares to pick each victim, much like Redis's
+/// `maxmemory-samples`.
+const EVICTION_SAMPLES: usize = 16;
+
+/// The keyspace held for a group of commands; see [`Keyspace::exclusive`].
+pub(crate) struct Exclusive<'a> {
+    keyspace: &'a Keyspace,
+    _gate: RwLockWriteGuard<'a, ()>,
+}
+
+impl Drop for Exclusive<'_> {
+    fn drop(&mut self) {
+        self.keyspace
+            .eviction_paused
+            .store(false, Ordering::Relaxed);
+    }
+}
+
+impl Keyspace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A keyspace that keeps its objects within `memory.max_memory` bytes.
+    pub fn with_memory(memory: MemoryConfig) -> Self {
+        Self {
+            memory,
+            ..Self::default()
+        }
+    }
+
+    /// Lets a command run alongside other commands but not during an exclusive operation.
+    pub(crate) fn shared(&self) -> RwLockReadGuard<'_, ()> {
+        self.gate.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Keeps every other command out, for groups of commands that must not be seen half
+    /// done.
+    ///
+    /// Nothing is evicted until the guard is dropped, since undoing a group couldn't bring
+    /// back objects evicted while it ran; writes that don't fit fail instead.
+    pub(crate) fn exclusive(&self) -> Exclusive<'_> {
+        let gate = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        self.eviction_paused.store(true, Ordering::Relaxed);
+        Exclusive {
+            keyspace: self,
+            _gate: gate,
+        }
+    }
+
+    /// Records what `undo` needs to put the object at `id` back as it is now. Only the
+    /// first call for an object counts, since that is the state to go back to.
+    pub(crate) fn save(&self, undo: &mut Undo, name: &str, id: &str) {
+        self.save_collection(undo, name);
+        if undo.saved.insert((name.to_string(), id.to_string())) {
+            let object = self
+                .collections
+                .get(name)
+                .and_then(|collection| collection.get(id).map(|object| object.clone()));
+            undo.objects
+                .push((name.to_string(), id.to_string(), object));
+        }
+    }
+
+    /// Records that `undo` must remove the collection again if it doesn't exist yet.
+    pub(crate) fn save_collection(&self, undo: &mut Undo, name: &str) {
+        if !self.collections.contains_key(name) && !undo.collections.iter().any(|c| c == name) {
+            undo.collections.push(name.to_string());
+        }
+    }
+
+    /// Puts back everything recorded in `undo`, logging it like any other write.
+    ///
+    /// The caller holds the keyspace exclusively from the first change to the end of this.
+    pub(crate) fn rollback(&self, undo: Undo) -> Result<(), StoreError> {
+        for (name, id, object) in undo.objects.into_iter().rev() {
+            match object {
+                Some(object) => {
+                    let collection = self.collection_or_create(&name);
+                    self.log_set(&name, &id, &object)?;
+                    self.put(&name, &collection, &id, object);
+                }
+                None => {
+                    let Some(collection) = self.collections.get(&name) else {
+                        continue;
+                    };
+                    if let Entry::Occupied(entry) = collection.entry(id) {
+                        self.remove_entry(&name, &collection, entry)?;
+                    };
+                }
+            }
+        }
+        for name in undo.collections {
+            if self.collections.get(&name).is_some_and(|c| c.is_empty()) {
+                self.log(&LogRecord::DropCollection {
+                    collection: Cow::Borrowed(&name),
+                })?;
+                self.remove_collection(&name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores `value` under a freshly generated id, creating the collection if needed.
+    pub fn insert(
+        &self,
+        name: &str,
+        value: Value,
+        expires_at: Option<u64>,
+    ) -> Result<String, StoreError> {
+        let object = Object::new(value, expires_at);
+        self.make_room(object.size)?;
+        let id = Ulid::new().to_string();
+        // Through `write`, so the key is locked before the unique indexes as it is by
+        // every other writer.
+        self.write_creating(name, |collection| {
+            self.write(name, collection, &id, |_| Ok(object))
+        })?;
+        Ok(id)
+    }
+
+    /// A copy of one live object.
+    pub fn get(&self, name: &str, id: &str) -> Result<Object, StoreError> {
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or(StoreError::CollectionNotFound)?;
+        let object = collection.get(id).ok_or(StoreError::ObjectNotFound)?;
+        if object.is_expired(now_millis()) {
+            drop(object);
+            self.expire(name, &collection, id)?;
+            return Err(StoreError::ObjectNotFound);
+        }
+        object.touch();
+        Ok(object.clone())
+    }
+
+    /// The live objects in a collection that `filter` matches, in key order, each turned
+    /// into an item by `read` so that only what the caller needs is copied out.
+    ///
+    /// Keys are looked up a batch at a time, so writers are never held off for the whole
+    /// read. A page picks up exactly where the cursor left off: objects written meanwhile
+    /// show up if their key is still ahead, and none are skipped or seen twice.
+    pub fn list<T>(
+        &self,
+        name: &str,
+        filter: &Filter,
+        range: &Range,
+        mut read: impl FnMut(&str, &Object) -> T,
+    ) -> Result<Page<T>, StoreError> {
+        const BATCH: usize = 256;
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or(StoreError::CollectionNotFound)?;
+        let now = now_millis();
+        let limit = range.limit.unwrap_or(usize::MAX);
+        let candidates = collection.candidates(filter);
+        let created = range.created();
+        let bounds = match &created {
+            Some((low, high)) => (
+                low.as_ref().map(String::as_str),
+                high.as_ref().map(String::as_str),
+            ),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        };
+        let mut items = Vec::new();
+        let mut position = range.cursor.clone();
+        loop {
+            let keys = match &candidates {
+                Some(ids) => keys_after(ids, position.as_deref(), range.descending, bounds, BATCH),
+                None => collection.keys_after(position.as_deref(), range.descending, bounds, BATCH),
+            };
+            if keys.is_empty() {
+                return Ok(Page { items, next: None });
+            }
+            for key in keys {
+                if items.len() == limit {
+                    return Ok(Page {
+                        items,
+                        next: position,
+                    });
+                }
+                // Only ULIDs tell when an object was made.
+                let in_range = created.is_none() || is_ulid(&key);
+                if let Some(object) = collection.get(&key).filter(|_| in_range) {
+                    if !object.is_expired(now) && filter.matches(&object.value) {
+                        items.push(read(&key, &object));
+                    }
+                }
+                position = Some(key);
+            }
+        }
+    }
+
+    pub fn has_collection(&self, name: &str) -> bool {
+        self.collections.contains_key(name)
+    }
+
+    /// Every collection with its size, sorted by name.
+    pub fn collections(&self) -> Vec<CollectionInfo> {
+        let now = now_millis();
+        let mut collections: Vec<_> = self
+            .collections
+            .iter()
+            .map(|collection| {
+                let (objects, bytes) = collection
+                    .iter()
+                    .filter(|object| !object.is_expired(now))
+                    .fold((0, 0), |(objects, bytes), object| {
+                        (objects + 1, bytes + object.size + object.key().len())
+                    });
+                CollectionInfo {
+                    name: collection.key().clone(),
+                    objects,
+                    bytes,
+                }
+            })
+            .collect();
+        collections.sort_by(|a, b| a.name.cmp(&b.name));
+        collections
+    }
+
+    /// Number of live objects in a collection that `filter` matches.
+    pub fn count(&self, name: &str, filter: &Filter) -> Result<usize, StoreError> {
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or(StoreError::CollectionNotFound)?;
+        let now = now_millis();
+        let live = |object: &Object| !object.is_expired(now) && filter.matches(&object.value);
+        Ok(match collection.candidates(filter) {
+            Some(ids) => ids
+                .iter()
+                .filter(|id| collection.get(*id).is_some_and(|object| live(&object)))
+                .count(),
+            None => collection.iter().filter(|object| live(object)).count(),
+        })
+    }
+
+    /// Declares an index on a collection, creating the collection if needed, and indexes
+    /// the objects already in it.
+    pub fn create_index(
+        &self,
+        name: &str,
+        spec: IndexSpec,
+        path: Pointer,
+    ) -> Result<(), StoreError> {
+        let _exclusive = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        let collection = self.collection_or_create(name);
+        if collection
+            .indexes()
+            .iter()
+            .any(|index| index.path() == &path)
+        {
+            return Err(StoreError::IndexExists);
+        }
+        let index = collection.build_index(spec, path);
+        if index.has_duplicates() {
+            return Err(StoreError::Duplicate(index.spec.path.clone()));
+        }
+        self.log(&LogRecord::CreateIndex {
+            collection: Cow::Borrowed(name),
+            spec: Cow::Borrowed(&index.spec),
+        })?;
+        collection
+            .indexes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(index);
+        Ok(())
+    }
+
+    /// Removes the index on `path` from a collection.
+    pub fn drop_index(&self, name: &str, path: &Pointer) -> Result<(), StoreError> {
+        let _exclusive = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or(StoreError::CollectionNotFound)?;
+        if !collection
+            .indexes()
+            .iter()
+            .any(|index| index.path() == path)
+        {
+            return Err(StoreError::IndexNotFound);
+        }
+        self.log(&LogRecord::DropIndex {
+            collection: Cow::Borrowed(name),
+            path: Cow::Owned(path.encoded()),
+        })?;
+        collection
+            .indexes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|index| index.path() != path);
+        Ok(())
+    }
+
+    /// Gives a collection a schema, creating the collection if needed, or takes its schema
+    /// away. Objects already stored are not checked; see [`Keyspace::validate`].
+    pub fn set_schema(&self, name: &str, schema: Option<Schema>) -> Result<(), StoreError> {
+        let _exclusive = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        let removing = schema.is_none();
+        let set = |collection: &Collection| {
+            self.log(&LogRecord::SetSchema {
+                collection: Cow::Borrowed(name),
+                schema: schema.as_ref().map(|schema| Cow::Borrowed(schema.source())),
+            })?;
+            collection.set_schema(schema);
+            Ok(())
+        };
+        match self.collections.get(name) {
+            Some(collection) => set(&collection),
+            None if removing => Err(StoreError::CollectionNotFound),
+            None => self.write_creating(name, set),
+        }
+    }
+
+    /// The schema of a collection as it was given, if it has one.
+    pub fn schema(&self, name: &str) -> Result<Option<Value>, StoreError> {
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or(StoreError::CollectionNotFound)?;
+        Ok(collection.schema().map(|schema| schema.source().clone()))
+    }
+
+    /// Checks the live objects of a collection against `schema`, or against the
+    /// collection's own schema if none is given, without changing anything.
+    pub fn validate(
+        &self,
+        name: &str,
+        schema: Option<Schema>,
+    ) -> Result<ValidationReport, StoreError> {
+        let schema = match schema {
+            Some(schema) => Arc::new(schema),
+            None => self
+                .collections
+                .get(name)
+                .ok_or(StoreError::CollectionNotFound)?
+                .schema()
+                .ok_or(StoreError::NoSchema)?,
+        };
+        let page = self.list(name, &Filter::All, &Range::default(), |id, object| {
+            (id.to_string(), schema.validate(&object.value))
+        })?;
+        Ok(ValidationReport {
+            checked: page.items.len(),
+            invalid: page
+                .items
+                .into_iter()
+                .filter(|(_, violations)| !violations.is_empty())
+                .collect(),
+        })
+    }
+
+    /// The indexes declared on a collection, in the order they were declared.
+    pub fn indexes(&self, name: &str) -> Result<Vec<IndexInfo>, StoreError> {
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or(StoreError::CollectionNotFound)?;
+        let indexes = collection.indexes();
+        Ok(indexes.iter().map(Index::info).collect())
+    }
+
+    /// Removes a collection along with its objects, returning how many objects it held.
+    pub fn drop_collection(&self, name: &str) -> Result<usize, StoreError> {
+        let _exclusive = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        if !self.collections.contains_key(name) {
+            return Err(StoreError::CollectionNotFound);
+        }
+        self.log(&LogRecord::DropCollection {
+            collection: Cow::Borrowed(name),
+        })?;
+        Ok(self.remove_collection(name))
+    }
+
+    /// Removes every object of a collection but keeps the collection, returning how many
+    /// objects were removed.
+    pub fn clear_collection(&self, name: &str) -> Result<usize, StoreError> {
+        let _exclusive = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        if !self.collections.contains_key(name) {
+            return Err(StoreError::CollectionNotFound);
+        }
+        self.log(&LogRecord::ClearCollection {
+            collection: Cow::Borrowed(name),
+        })?;
+        Ok(self.empty_collection(name))
+    }
+
+    /// Gives a collection a new name that no other collection has.
+    ///
+    /// No command runs while the objects move, so every command sees the collection under
+    /// exactly one of the two names.
+    pub fn rename_collection(&self, from: &str, to: &str) -> Result<(), StoreError> {
+        let _exclusive = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        if !self.collections.contains_key(from) {
+            return Err(StoreError::CollectionNotFound);
+        }
+        if self.collections.contains_key(to) {
+            return Err(StoreError::CollectionExists);
+        }
+        self.log(&LogRecord::RenameCollection {
+            from: Cow::Borrowed(from),
+            to: Cow::Borrowed(to),
+        })?;
+        self.move_collection(from, to);
+        Ok(())
+    }
+
+    fn remove_collection(&self, name: &str) -> usize {
+        let Some((_, collection)) = self.collections.remove(name) else {
+            return 0;
+        };
+        let removed = collection.len();
+        self.forget(name, &collection);
+        removed
+    }
+
+    fn empty_collection(&self, name: &str) -> usize {
+        let Some(collection) = self.collections.get(name) else {
+            return 0;
+        };
+        self.forget(name, &collection);
+        let removed = collection.len();
+        collection.clear();
+        removed
+    }
+
+    fn move_collection(&self, from: &str, to: &str) {
+        let Some((_, collection)) = self.collections.remove(from) else {
+            return;
+        };
+        self.forget(from, &collection);
+        for object in collection.iter() {
+            self.replaced(to, &collection, object.key(), None, Some(object.value()));
+        }
+        self.collections.insert(to.to_string(), collection);
+    }
+
+    /// Takes a collection's objects out of the memory count and the expiry schedule.
+    fn forget(&self, name: &str, collection: &Collection) {
+        for object in collection.iter() {
+            self.replaced(name, collection, object.key(), Some(object.value()), None);
+        }
+    }
+
+    /// Replaces an existing object with what `f` makes of it, returning its new version.
+    pub fn modify(
+        &self,
+        name: &str,
+        id: &str,
+        f: impl FnOnce(&Object) -> Result<Object, StoreError>,
+    ) -> Result<u64, StoreError> {
+        self.make_room(0)?;
+        let version = {
+            let collection = self
+                .collections
+                .get(name)
+                .ok_or(StoreError::CollectionNotFound)?;
+            self.write(name, &collection, id, |current| match current {
+                Some(object) => f(object),
+                None => Err(StoreError::ObjectNotFound),
+            })?
+        };
+        // Other objects can only be evicted once this one is unlocked.
+        let _ = self.make_room(0);
+        Ok(version)
+    }
+
+    /// Stores what `f` makes of the object at `id`, which it sees as `None` if there is no
+    /// such object yet. Creates the collection if needed.
+    ///
+    /// Returns the version of the stored object.
+    pub fn upsert(
+        &self,
+        name: &str,
+        id: &str,
+        f: impl FnOnce(Option<&Object>) -> Result<Object, StoreError>,
+    ) -> Result<u64, StoreError> {
+        self.make_room(0)?;
+        let version =
+            self.write_creating(name, |collection| self.write(name, collection, id, f))?;
+        // Other objects can only be evicted once this one is unlocked.
+        let _ = self.make_room(0);
+        Ok(version)
+    }
+
+    /// Applies `f` to an object and stores the result.
+    ///
+    /// The object stays locked from the time `f` sees it until the result is logged and
+    /// stored, so concurrent writers to the same object can't interleave.
+    fn write(
+        &self,
+        name: &str,
+        collection: &Collection,
+        id: &str,
+        f: impl FnOnce(Option<&Object>) -> Result<Object, StoreError>,
+    ) -> Result<u64, StoreError> {
+        let entry = collection.entry(id.to_string());
+        let expired = matches!(&entry, Entry::Occupied(e) if e.get().is_expired(now_millis()));
+        let current = match &entry {
+            Entry::Occupied(e) if !expired => Some(e.get()),
+            _ => None,
+        };
+        let mut object = match f(current) {
+            Ok(object) => object,
+            Err(e) => {
+                if let (Entry::Occupied(entry), true) = (entry, expired) {
+                    self.remove_entry(name, collection, entry)?;
+                }
+                return Err(e);
+            }
+        };
+        let old = match &entry {
+            Entry::Occupied(e) => Some(e.get()),
+            Entry::Vacant(_) => None,
+        };
+        self.check_growth(old.map_or(0, |o| o.size), object.size)?;
+        collection.check_schema(&object.value)?;
+        let _unique = collection.lock_unique();
+        collection.check_unique(id, &object.value)?;
+        object.version = self.next_version();
+        let version = object.version;
+        self.log_set(name, id, &object)?;
+        self.replaced(name, collection, id, old, Some(&object));
+        match entry {
+            Entry::Occupied(mut entry) => {
+                entry.insert(object);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(object);
+            }
+        }
+        Ok(version)
+    }
+
+    /// Deletes an object, if it is at version `if_match` when one is given.
+    pub fn remove(&self, name: &str, id: &str, if_match: Option<u64>) -> Result<(), StoreError> {
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or(StoreError::CollectionNotFound)?;
+        let Entry::Occupied(entry) = collection.entry(id.to_string()) else {
+            return Err(StoreError::ObjectNotFound);
+        };
+        if entry.get().is_expired(now_millis()) {
+            self.remove_entry(name, &collection, entry)?;
+            return Err(StoreError::ObjectNotFound);
+        }
+        check_version(Some(entry.get()), if_match)?;
+        self.remove_entry(name, &collection, entry)?;
+        Ok(())
+    }
+
+    /// Logs the delete of a locked object, then removes it.
+    fn remove_entry(
+        &self,
+        name: &str,
+        collection: &Collection,
+        entry: OccupiedEntry<'_, String, Object>,
+    ) -> Result<Object, StoreError> {
+        self.log(&LogRecord::Delete {
+            collection: Cow::Borrowed(name),
+            id: Cow::Borrowed(entry.key()),
+        })?;
+        let (id, object) = entry.remove_entry();
+        self.replaced(name, collection, &id, Some(&object), None);
+        Ok(object)
+    }
+
+    /// Deletes every object whose ULID key was made before `before`, a Unix time in
+    /// milliseconds. Only keys in that range are visited, so the cost grows with what is
+    /// deleted rather than with the collection.
+    ///
+    /// Returns how many live objects were deleted.
+    pub fn purge(&self, name: &str, before: u64) -> Result<usize, StoreError> {
+        const BATCH: usize = 256;
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or(StoreError::CollectionNotFound)?;
+        let end = first_ulid(before);
+        let now = now_millis();
+        let mut removed = 0;
+        let mut position = None;
+        loop {
+            let bounds = (Bound::Unbounded, Bound::Excluded(end.as_str()));
+            let keys = collection.keys_after(position.as_deref(), false, bounds, BATCH);
+            let Some(last) = keys.last().cloned() else {
+                return Ok(removed);
+            };
+            for key in keys.into_iter().filter(|key| is_ulid(key)) {
+                if let Entry::Occupied(entry) = collection.entry(key) {
+                    let live = !entry.get().is_expired(now);
+                    self.remove_entry(name, &collection, entry)?;
+                    removed += usize::from(live);
+                }
+            }
+            position = Some(last);
+        }
+    }
+
+    /// Gives an object a new expiry time, or none to keep it forever.
+    pub fn set_expiry(
+        &self,
+        name: &str,
+        id: &str,
+        expires_at: Option<u64>,
+    ) -> Result<(), StoreError> {
+        let collection = self
+            .collections
+            .get(name)
+            .ok_or(StoreError::CollectionNotFound)?;
+        let Entry::Occupied(mut entry) = collection.entry(id.to_string()) else {
+            return Err(StoreError::ObjectNotFound);
+        };
+        if entry.get().is_expired(now_millis()) {
+            self.remove_entry(name, &collection, entry)?;
+            return Err(StoreError::ObjectNotFound);
+        }
+        self.log(&LogRecord::Expire {
+            collection: Cow::Borrowed(name),
+            id: Cow::Borrowed(id),
+            expires_at,
+        })?;
+        self.track_expiry(name, id, entry.get().expires_at, expires_at);
+        entry.get_mut().expires_at = expires_at;
+        Ok(())
+    }
+
+    /// Deletes objects whose time has run out, whether or not anyone reads them again.
+    ///
+    /// Returns how many were deleted.
+    pub fn remove_expired(&self) -> Result<usize, StoreError> {
+        let _shared = self.shared();
+        let now = now_millis();
+        let mut removed = 0;
+        loop {
+            let due = {
+                let mut expiries = self.expiries.lock().unwrap_or_else(PoisonError::into_inner);
+                match expiries.first() {
+                    Some((at, _, _)) if *at <= now => expiries.pop_first(),
+                    _ => None,
+                }
+            };
+            let Some((_, name, id)) = due else {
+                return Ok(removed);
+            };
+            if let Some(collection) = self.collections.get(&name) {
+                if self.expire(&name, &collection, &id)? {
+                    removed += 1;
+                }
+            }
+        }
+    }
+
+    /// Deletes one object if it has expired, logging the delete like any other.
+    fn expire(&self, name: &str, collection: &Collection, id: &str) -> Result<bool, StoreError> {
+        let Entry::Occupied(entry) = collection.entry(id.to_string()) else {
+            return Ok(false);
+        };
+        if !entry.get().is_expired(now_millis()) {
+            return Ok(false);
+        }
+        self.remove_entry(name, collection, entry)?;
+        Ok(true)
+    }
+
+    /// Evicts objects until `needed` more bytes fit under the memory limit.
+    ///
+    /// Must be called without holding any object, since the victims have to be locked.
+    /// Evicting goes a little below the limit so that the next few writes don't each have
+    /// to look for victims again.
+    fn make_room(&self, needed: usize) -> Result<(), StoreError> {
+        let max = self.memory.max_memory;
+        let fits = || self.used_memory.load(Ordering::Relaxed) + needed <= max;
+        if max == 0 || fits() {
+            return Ok(());
+        }
+        if !self.evicts() {
+            return Err(StoreError::OutOfMemory);
+        }
+        let mut hand = self.evicting.lock().unwrap_or_else(PoisonError::into_inner);
+        if fits() {
+            return Ok(());
+        }
+        let target = (max - max / 32).saturating_sub(needed);
+        let mut expiring = None;
+        while self.used_memory.load(Ordering::Relaxed) > target {
+            let victim = match self.memory.policy {
+                EvictionPolicy::VolatileTtl => self.next_expiring(&mut expiring),
+                _ => self.sample_victim(&mut hand),
+            };
+            let Some((name, id)) = victim else {
+                break;
+            };
+            let Some(collection) = self.collections.get(&name) else {
+                continue;
+            };
+            let Entry::Occupied(entry) = collection.entry(id) else {
+                continue;
+            };
+            self.remove_entry(&name, &collection, entry)?;
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        if fits() {
+            Ok(())
+        } else {
+            Err(StoreError::OutOfMemory)
+        }
+    }
+
+    /// Whether writes that don't fit may evict other objects to make room.
+    fn evicts(&self) -> bool {
+        self.memory.policy != EvictionPolicy::NoEviction
+            && !self.eviction_paused.load(Ordering::Relaxed)
+    }
+
+    /// When nothing may be evicted, refuses to let an object grow past the limit.
+    fn check_growth(&self, old: usize, new: usize) -> Result<(), StoreError> {
+        let max = self.memory.max_memory;
+        if max != 0
+            && !self.evicts()
+            && new > old
+            && self.used_memory.load(Ordering::Relaxed) + (new - old) > max
+        {
+            return Err(StoreError::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    /// The object due to expire soonest after `last`, which moves on to it.
+    fn next_expiring(&self, last: &mut Option<(u64, String, String)>) -> Option<(String, String)> {
+        let expiries = self.expiries.lock().unwrap_or_else(PoisonError::into_inner);
+        let next = match last.as_ref() {
+            Some(last) => expiries
+                .range((Bound::Excluded(last), Bound::Unbounded))
+                .next(),
+            None => expiries.first(),
+        }
+        .cloned()?;
+        let (_, name, id) = last.insert(next);
+        Some((name.clone(), id.clone()))
+    }
+
+    /// The best victim among the next [`EVICTION_SAMPLES`] objects after `hand`, which
+    /// moves on past them. Collections are swept in name order and their objects in key
+    /// order, starting over at the first collection after the last.
+    fn sample_victim(&self, hand: &mut EvictionHand) -> Option<(String, String)> {
+        let mut names: Vec<String> = self.collections.iter().map(|c| c.key().clone()).collect();
+        names.sort_unstable();
+        let start = names.partition_point(|name| *name < hand.collection);
+        let resumed = names
+            .get(start)
+            .is_some_and(|name| *name == hand.collection);
+        // From the hand to the end, then from the start back round to the hand.
+        let mut passes: Vec<_> = names[start..]
+            .iter()
+            .chain(&names[..start])
+            .enumerate()
+            .map(|(i, name)| (name, (i == 0 && resumed).then_some(hand.key.as_str())))
+            .map(|(name, after)| (name, after, Bound::Unbounded))
+            .collect();
+        if resumed {
+            passes.push((&names[start], None, Bound::Included(hand.key.as_str())));
+        }
+
+        let random = RandomState::new();
+        let mut sample = Vec::with_capacity(EVICTION_SAMPLES);
+        let mut last = None;
+        for (name, after, high) in passes {
+            let Some(collection) = self.collections.get(name) else {
+                continue;
+            };
+            let keys = collection.keys_after(
+                after,
+                false,
+                (Bound::Unbounded, high),
+                EVICTION_SAMPLES - sample.len(),
+            );
+            for key in keys {
+                if let Some(object) = collection.get(&key) {
+                    let score = match self.memory.policy {
+                        EvictionPolicy::AllkeysLfu => object.hits.load(Ordering::Relaxed),
+                        EvictionPolicy::Random => random.hash_one(&key),
+                        _ => object.last_access.load(Ordering::Relaxed),
+                    };
+                    sample.push((score, name, key.clone()));
+                }
+                last = Some((name, key));
+            }
+            if sample.len() == EVICTION_SAMPLES {
+                break;
+            }
+        }
+        if let Some((name, key)) = last {
+            hand.collection.clone_from(name);
+            hand.key = key;
+        }
+        let (_, name, id) = sample.into_iter().min_by_key(|(score, _, _)| *score)?;
+        Some((name.clone(), id))
+    }
+
+    /// Keeps memory accounting, the expiry schedule and the collection's key order and
+    /// indexes in step with an object being stored (`new`), replaced (`old` and `new`) or
+    /// removed (`old`).
+    fn replaced(
+        &self,
+        name: &str,
+        collection: &Collection,
+        id: &str,
+        old: Option<&Object>,
+        new: Option<&Object>,
+    ) {
+        if old.is_some() != new.is_some() {
+            let mut order = collection
+                .order
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            if new.is_some() {
+                order.insert(id.to_string());
+            } else {
+                order.remove(id);
+            }
+        }
+        collection.reindex(id, old.map(|o| &o.value), new.map(|o| &o.value));
+        // The key is stored once per object, so it only counts when one comes or goes.
+        if let Some(old) = old {
+            self.used_memory
+                .fetch_sub(old.size + id.len(), Ordering::Relaxed);
+        }
+        if let Some(new) = new {
+            self.used_memory
+                .fetch_add(new.size + id.len(), Ordering::Relaxed);
+        }
+        self.track_expiry(
+            name,
+            id,
+            old.and_then(|o| o.expires_at),
+            new.and_then(|o| o.expires_at),
+        );
+    }
+
+    /// Keeps the expiry schedule in step with an object's expiry time changing.
+    fn track_expiry(&self, name: &str, id: &str, old: Option<u64>, new: Option<u64>) {
+        if old == new {
+            return;
+        }
+        let mut expiries = self.expiries.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(at) = old {
+            expiries.remove(&(at, name.to_string(), id.to_string()));
+        }
+        if let Some(at) = new {
+            expiries.insert((at, name.to_string(), id.to_string()));
+        }
+    }
+
+    /// Stores `object` without logging it, for replay and LOAD.
+    fn put(&self, name: &str, collection: &Collection, id: &str, object: Object) {
+        match collection.entry(id.to_string()) {
+            Entry::Occupied(mut entry) => {
+                self.replaced(name, collection, id, Some(entry.get()), Some(&object));
+                entry.insert(object);
+            }
+            Entry::Vacant(entry) => {
+                self.replaced(name, collection, id, None, Some(&object));
+                entry.insert(object);
+            }
+        }
+    }
+
+    /// Runs `write` on the collection called `name`. A missing collection is only added
+    /// once `write` has succeeded on it, so a rejected write leaves none behind; until
+    /// then no other writer can create it.
+    fn write_creating<T>(
+        &self,
+        name: &str,
+        write: impl FnOnce(&Collection) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        if let Some(collection) = self.collections.get(name) {
+            return write(&collection);
+        }
+        match self.collections.entry(name.to_string()) {
+            Entry::Occupied(entry) => write(entry.get()),
+            Entry::Vacant(entry) => {
+                let collection = Collection::default();
+                let written = write(&collection)?;
+                entry.insert(collection);
+                Ok(written)
+            }
+        }
+    }
+
+    fn collection_or_create(&self, name: &str) -> Ref<'_, String, Collection> {
+        match self.collections.get(name) {
+            Some(collection) => collection,
+            None => self
+                .collections
+                .entry(name.to_string())
+                .or_default()
+                .downgrade(),
+        }
+    }
+
+    fn log_set(&self, name: &str, id: &str, object: &Object) -> Result<(), AofError> {
+        self.log(&LogRecord::Set {
+            collection: Cow::Borrowed(name),
+            id: Cow::Borrowed(id),
+            value: Cow::Borrowed(&object.value),
+            expires_at: object.expires_at,
+            version: object.version,
+        })
+    }
+
+    fn next_version(&self) -> u64 {
+        self.versions.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Keeps a version read back from the log; logs written before objects
+    /// had versions get a fresh one.
+    fn restore_version(&self, object: &mut Object, version: u64) {
+        if version == 0 {
+            object.version = self.next_version();
+        } else {
+            object.version = version;
+            self.versions.fetch_max(version, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a mutation in the append-only log, if there is one.
+    ///
+    /// Callers log before changing memory, so a failed append leaves the object as it was.
+    fn log(&self, record: &LogRecord) -> Result<(), AofError> {
+        match self.log.get() {
+            Some(aof) => aof.append(record),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs `records` all together or not at all.
+    fn log_all(&self, records: &[LogRecord]) -> Result<(), AofError> {
+        match self.log.get() {
+            Some(aof) => aof.append_all(records),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies the records of an existing log on top of the current contents, then keeps
+    /// appending new mutations to it.
+    pub fn open_log(&self, path: &Path, config: LogConfig) -> Result<ReplayReport, AofError> {
+        let report = Aof::replay(path, |record| self.apply(record))?;
+        if self.log.set(Aof::open(path, config)?).is_err() {
+            panic!("append-only log opened twice");
+        }
+        Ok(report)
+    }
+
+    /// Forces the log to disk; called every second under [`FsyncPolicy::Everysec`].
+    pub fn sync_log(&self) -> Result<(), AofError> {
+        match self.log.get() {
+            Some(aof) if aof.policy() == FsyncPolicy::Everysec => Ok(aof.sync()?),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the log has grown past the automatic rewrite threshold.
+    pub fn log_needs_rewrite(&self) -> bool {
+        self.log.get().is_some_and(Aof::needs_rewrite)
+    }
+
+    /// Compacts the log into the shortest one that rebuilds the current contents.
+    ///
+    /// Only capturing the contents holds writers off; the new log is written on a blocking
+    /// thread while commands keep running, and their records are carried over to it.
+    pub fn start_log_rewrite(self: &Arc<Self>) -> Result<(), AofError> {
+        let aof = self.log.get().ok_or(AofError::Disabled)?;
+        let snapshot = {
+            let _exclusive = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+            let objects = self.collections.iter().map(|c| c.len() as u64).sum();
+            aof.begin_rewrite(objects)?;
+            self.capture()
+        };
+        let kv = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let Some(aof) = kv.log.get() else {
+                return;
+            };
+            match aof.rewrite(&snapshot) {
+                Ok(size) => info!("Rewrote append-only log, now {} bytes", size),
+                Err(e) => error!("Append-only log rewrite failed: {}", e),
+            }
+        });
+        Ok(())
+    }
+
+    /// Counts and log status for the INFO command.
+    pub fn info(&self) -> Value {
+        json!({
+            "collections": self.collections.len(),
+            "objects": self.collections.iter().map(|c| c.len()).sum::<usize>(),
+            "memory": {
+                "used": self.used_memory.load(Ordering::Relaxed),
+                "max": self.memory.max_memory,
+                "policy": self.memory.policy.to_possible_value().map(|v| v.get_name().to_string()),
+                "evicted": self.evicted.load(Ordering::Relaxed),
+            },
+            "log": self.log.get().map(Aof::info),
+        })
+    }
+
+    fn apply(&self, record: LogRecord) {
+        match record {
+            LogRecord::Set {
+                collection,
+                id,
+                value,
+                expires_at,
+                version,
+            } => {
+                let mut object = Object::new(value.into_owned(), expires_at);
+                self.restore_version(&mut object, version);
+                let collection_ref = self.collection_or_create(&collection);
+                if object.is_expired(now_millis()) {
+                    if let Some((_, old)) = collection_ref.remove(id.as_ref()) {
+                        self.replaced(&collection, &collection_ref, &id, Some(&old), None);
+                    }
+                } else {
+                    self.put(&collection, &collection_ref, &id, object);
+                }
+            }
+            LogRecord::Delete { collection, id } => {
+                if let Some(collection_ref) = self.collections.get(collection.as_ref()) {
+                    if let Some((_, old)) = collection_ref.remove(id.as_ref()) {
+                        self.replaced(&collection, &collection_ref, &id, Some(&old), None);
+                    }
+                }
+            }
+            LogRecord::Expire {
+                collection,
+                id,
+                expires_at,
+            } => {
+                if let Some(collection_ref) = self.collections.get(collection.as_ref()) {
+                    if let Some(mut object) = collection_ref.get_mut(id.as_ref()) {
+                        self.track_expiry(&collection, &id, object.expires_at, expires_at);
+                        object.expires_at = expires_at;
+                    }
+                }
+            }
+            LogRecord::Flush => self.clear(),
+            LogRecord::DropCollection { collection } => {
+                self.remove_collection(&collection);
+            }
+            LogRecord::ClearCollection { collection } => {
+                self.empty_collection(&collection);
+            }
+            LogRecord::RenameCollection { from, to } => {
+                if !self.collections.contains_key(to.as_ref()) {
+                    self.move_collection(&from, &to);
+                }
+            }
+            LogRecord::CreateIndex { collection, spec } => {
+                self.restore_index(&collection, spec.into_owned());
+            }
+            LogRecord::SetSchema { collection, schema } => {
+                let schema = schema.and_then(|schema| Schema::compile(schema.into_owned()).ok());
+                if schema.is_some() {
+                    self.collection_or_create(&collection).set_schema(schema);
+                } else if let Some(collection) = self.collections.get(collection.as_ref()) {
+                    collection.set_schema(None);
+                }
+            }
+            LogRecord::DropIndex { collection, path } => {
+                if let Some(collection) = self.collections.get(collection.as_ref()) {
+                    collection
+                        .indexes
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .retain(|index| index.spec.path != path);
+                }
+            }
+        }
+    }
+
+    /// Declares an index read back from the log or a snapshot, unless the collection
+    /// already has one on that path.
+    fn restore_index(&self, name: &str, spec: IndexSpec) {
+        let Ok(path) = Pointer::parse(&spec.path) else {
+            return;
+        };
+        let collection = self.collection_or_create(name);
+        if collection
+            .indexes()
+            .iter()
+            .any(|index| index.path() == &path)
+        {
+            return;
+        }
+        let index = collection.build_index(spec, path);
+        collection
+            .indexes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(index);
+    }
+
+    /// Drops every collection along with the expiry schedule.
+    fn clear(&self) {
+        self.collections.clear();
+        self.used_memory.store(0, Ordering::Relaxed);
+        self.expiries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Copies every collection at a single point in time.
+    ///
+    /// Writers are only held off while the objects are cloned; encoding and writing the
+    /// snapshot happen afterwards without any lock.
+    pub fn snapshot(&self) -> Snapshot {
+        let _exclusive = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        self.capture()
+    }
+
+    /// Copies every collection; the caller holds the gate exclusively.
+    fn capture(&self) -> Snapshot {
+        let now = now_millis();
+        let collections = self
+            .collections
+            .iter()
+            .map(|collection| CollectionDump {
+                name: collection.key().clone(),
+                objects: collection
+                    .value()
+                    .iter()
+                    .filter(|object| !object.is_expired(now))
+                    .map(|object| ObjectDump {
+                        id: object.key().to_string(),
+                        value: object.value.clone(),
+                        expires_at: object.expires_at,
+                        version: object.version,
+                    })
+                    .collect(),
+                indexes: collection.index_specs(),
+                schema: collection.schema().map(|schema| schema.source().clone()),
+            })
+            .collect();
+        Snapshot { collections }
+    }
+
+    /// Puts the contents of `snapshot` into the keyspace.
+    ///
+    /// Every object is checked before the keyspace is touched, and the swap happens while
+    /// no other command runs, so a bad snapshot never leaves a half-loaded store behind.
+    pub fn load(&self, snapshot: Snapshot, mode: LoadMode) -> Result<LoadReport, SnapshotError> {
+        let mut report = LoadReport::default();
+        let mut loaded = Vec::with_capacity(snapshot.collections.len());
+        let now = now_millis();
+        for collection in snapshot.collections {
+            let mut objects = BTreeMap::new();
+            for object in collection.objects {
+                let id = object.id;
+                // Loading counts as a write, so versions clients hold from before go stale.
+                let mut object = Object::new(object.value, object.expires_at);
+                object.version = self.next_version();
+                if !object.is_expired(now) {
+                    objects.insert(id, object);
+                }
+            }
+            report.collections += 1;
+            report.objects += objects.len();
+            let schema = collection.schema.map(Schema::compile).transpose()?;
+            loaded.push((collection.name, objects, collection.indexes, schema));
+        }
+
+        let _exclusive = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        // Logged in one write before memory changes, so a failure leaves both as they were.
+        let mut records = Vec::new();
+        if mode == LoadMode::Replace {
+            records.push(LogRecord::Flush);
+        }
+        for (name, objects, indexes, schema) in &loaded {
+            for (id, object) in objects {
+                records.push(LogRecord::Set {
+                    collection: Cow::Borrowed(name),
+                    id: Cow::Borrowed(id),
+                    value: Cow::Borrowed(&object.value),
+                    expires_at: object.expires_at,
+                    version: object.version,
+                });
+            }
+            for spec in indexes {
+                records.push(LogRecord::CreateIndex {
+                    collection: Cow::Borrowed(name),
+                    spec: Cow::Borrowed(spec),
+                });
+            }
+            if let Some(schema) = schema {
+                records.push(LogRecord::SetSchema {
+                    collection: Cow::Borrowed(name),
+                    schema: Some(Cow::Borrowed(schema.source())),
+                });
+            }
+        }
+        self.log_all(&records)?;
+        if mode == LoadMode::Replace {
+            self.clear();
+        }
+        for (name, objects, indexes, schema) in loaded {
+            {
+                let collection = self.collection_or_create(&name);
+                for (id, object) in objects {
+                    self.put(&name, &collection, &id, object);
+                }
+                if schema.is_some() {
+                    collection.set_schema(schema);
+                }
+            }
+            for spec in indexes {
+                self.restore_index(&name, spec);
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Fails unless `object` exists and is at version `if_match`, when one is given.
+pub(crate) fn check_version(
+    object: Option<&Object>,
+    if_match: Option<u64>,
+) -> Result<(), StoreError> {
+    match if_match {
+        Some(version) if object.map(|o| o.version) != Some(version) => {
+            Err(StoreError::VersionConflict(version))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn now_millis() -> u64 {
+    unix_millis(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::Map;
+
+    use super::*;
+    use crate::index::IndexKind;
+
+    fn put(kv: &Keyspace, id: &str, n: i64) {
+        kv.upsert("c", id, |_| Ok(Object::new(json!({"n": n}), None)))
+            .unwrap();
+    }
+
+    /// Keys `k0` to `k9`, with `n` set to the digit.
+    fn keyspace() -> Keyspace {
+        let kv = Keyspace::new();
+        for i in 0..10 {
+            put(&kv, &format!("k{}", i), i);
+        }
+        kv
+    }
+
+    fn page(kv: &Keyspace, filter: &Filter, range: &Range) -> Page<String> {
+        kv.list("c", filter, range, |id, _| id.to_string()).unwrap()
+    }
+
+    /// Every key, read `limit` at a time by following the cursors.
+    fn walk(kv: &Keyspace, filter: &Filter, descending: bool, limit: usize) -> Vec<String> {
+        let mut range = Range {
+            descending,
+            limit: Some(limit),
+            ..Range::default()
+        };
+        let mut keys = Vec::new();
+        loop {
+            let page = page(kv, filter, &range);
+            assert!(page.items.len() <= limit);
+            keys.extend(page.items);
+            match page.next {
+                Some(next) => range.cursor = Some(next),
+                None => return keys,
+            }
+        }
+    }
+
+    fn filter(query: Value) -> Filter {
+        let mut headers = Map::new();
+        headers.insert("where".to_string(), query);
+        Filter::from_headers(&Some(headers)).unwrap()
+    }
+
+    #[test]
+    fn cursors_walk_every_key_once() {
+        let kv = keyspace();
+        let keys: Vec<_> = (0..10).map(|i| format!("k{}", i)).collect();
+        for limit in [1, 3, 10, 11] {
+            assert_eq!(walk(&kv, &Filter::All, false, limit), keys);
+            let mut descending = walk(&kv, &Filter::All, true, limit);
+            descending.reverse();
+            assert_eq!(descending, keys);
+        }
+        let odd = filter(json!({"n": {"$in": [1, 3, 5, 7, 9]}}));
+        assert_eq!(walk(&kv, &odd, false, 2), ["k1", "k3", "k5", "k7", "k9"]);
+    }
+
+    #[test]
+    fn indexed_pages_match_scanned_ones() {
+        let kv = keyspace();
+        let at_least_four = filter(json!({"n": {"$gte": 4}}));
+        let scanned = walk(&kv, &at_least_four, true, 4);
+        let spec = IndexSpec {
+            path: "/n".to_string(),
+            kind: IndexKind::Ordered,
+            unique: false,
+        };
+        kv.create_index("c", spec, Pointer::parse("/n").unwrap())
+            .unwrap();
+        assert_eq!(walk(&kv, &at_least_four, true, 4), scanned);
+        assert_eq!(scanned, ["k9", "k8", "k7", "k6", "k5", "k4"]);
+    }
+
+    #[test]
+    fn pages_pick_up_where_the_cursor_left_off() {
+        let kv = keyspace();
+        let mut range = Range {
+            limit: Some(3),
+            ..Range::default()
+        };
+        let first = page(&kv, &Filter::All, &range);
+        assert_eq!(first.items, ["k0", "k1", "k2"]);
+        // A key behind the cursor is not seen, one ahead of it is, and removing the key
+        // the cursor points at doesn't lose the place.
+        put(&kv, "k00", 0);
+        put(&kv, "k21", 0);
+        kv.remove("c", "k2", None).unwrap();
+        range.cursor = first.next;
+        assert_eq!(page(&kv, &Filter::All, &range).items, ["k21", "k3", "k4"]);
+    }
+
+    #[test]
+    fn reads_of_a_missing_collection_fail() {
+        let read = Keyspace::new().list("c", &Filter::All, &Range::default(), |_, _| ());
+        assert!(matches!(read, Err(StoreError::CollectionNotFound)));
+    }
+
+    fn n(kv: &Keyspace, name: &str, id: &str) -> Option<Value> {
+        kv.get(name, id)
+            .ok()
+            .map(|object| object.value["n"].clone())
+    }
+
+    #[test]
+    fn rollback_puts_back_what_the_group_changed() {
+        let kv = keyspace();
+        let mut undo = Undo::default();
+        kv.save(&mut undo, "c", "k1");
+        put(&kv, "k1", 10);
+        // Only the state before the first change counts.
+        kv.save(&mut undo, "c", "k1");
+        put(&kv, "k1", 11);
+        kv.save(&mut undo, "c", "k2");
+        kv.remove("c", "k2", None).unwrap();
+        kv.save(&mut undo, "c", "new");
+        put(&kv, "new", 12);
+        let id = kv.insert("c", json!({"n": 13}), None).unwrap();
+        undo.created("c", &id);
+        kv.save(&mut undo, "other", "k0");
+        kv.upsert("other", "k0", |_| Ok(Object::new(json!({}), None)))
+            .unwrap();
+
+        kv.rollback(undo).unwrap();
+        assert_eq!(n(&kv, "c", "k1"), Some(json!(1)));
+        assert_eq!(n(&kv, "c", "k2"), Some(json!(2)));
+        assert_eq!(n(&kv, "c", "new"), None);
+        assert_eq!(n(&kv, "c", &id), None);
+        assert_eq!(kv.count("c", &Filter::All).unwrap(), 10);
+        assert!(!kv.has_collection("other"));
+    }
+
+    #[test]
+    fn rollback_keeps_collections_that_existed() {
+        let kv = keyspace();
+        kv.clear_collection("c").unwrap();
+        let mut undo = Undo::default();
+        kv.save(&mut undo, "c", "k0");
+        put(&kv, "k0", 0);
+        kv.rollback(undo).unwrap();
+        assert!(kv.has_collection("c"));
+        assert_eq!(kv.count("c", &Filter::All).unwrap(), 0);
+    }
+
+    #[test]
+    fn creation_bounds_only_take_ulid_keys_in_range() {
+        let kv = Keyspace::new();
+        let made_at: Vec<_> = [1000, 2000, 3000]
+            .into_iter()
+            .map(|ms| Ulid::from_parts(ms, 7).to_string())
+            .collect();
+        for id in made_at.iter().map(String::as_str).chain(["k0", "zz"]) {
+            put(&kv, id, 0);
+        }
+        let created = |after, before| {
+            let range = Range {
+                created_after: after,
+                created_before: before,
+                ..Range::default()
+            };
+            page(&kv, &Filter::All, &range).items
+        };
+        assert_eq!(created(Some(1000), None), made_at[1..]);
+        assert_eq!(created(None, Some(3000)), made_at[..2]);
+        assert_eq!(created(Some(999), Some(2001)), made_at[..2]);
+        assert!(created(Some(3000), Some(1000)).is_empty());
+        assert!(created(Some(u64::MAX), None).is_empty());
+        assert_eq!(created(Some(0), Some(u64::MAX)), made_at);
+
+        assert_eq!(kv.purge("c", 2500).unwrap(), 2);
+        assert_eq!(
+            walk(&kv, &Filter::All, false, 10),
+            [&made_at[2], "k0", "zz"]
+        );
+    }
+
+    #[test]
+    fn posts_and_puts_on_a_unique_index_run_side_by_side() {
+        let kv = Arc::new(Keyspace::new());
+        let spec = IndexSpec {
+            path: "/n".to_string(),
+            kind: IndexKind::Hash,
+            unique: true,
+        };
+        kv.create_index("c", spec, Pointer::parse("/n").unwrap())
+            .unwrap();
+        let (done, finished) = mpsc::channel();
+        for writer in 0..4 {
+            let (kv, done) = (kv.clone(), done.clone());
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    let n = writer * 10_000 + i;
+                    if writer % 2 == 0 {
+                        kv.insert("c", json!({"n": n}), None).unwrap();
+                    } else {
+                        put(&kv, &format!("k{}", n), n);
+                    }
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..4 {
+            finished
+                .recv_timeout(Duration::from_secs(30))
+                .expect("writers deadlocked");
+        }
+        assert_eq!(kv.count("c", &Filter::All).unwrap(), 8000);
+    }
+
+    #[test]
+    fn groups_fail_writes_that_dont_fit_instead_of_evicting() {
+        let sized = Keyspace::new();
+        put(&sized, "k0", 0);
+        let size = sized.used_memory.load(Ordering::Relaxed);
+        let kv = Keyspace::with_memory(MemoryConfig {
+            max_memory: 4 * size + size / 2,
+            policy: EvictionPolicy::AllkeysLru,
+        });
+        for i in 0..4 {
+            put(&kv, &format!("k{}", i), i);
+        }
+        {
+            let _exclusive = kv.exclusive();
+            let write = kv.upsert("c", "k4", |_| Ok(Object::new(json!({"n": 4}), None)));
+            assert!(matches!(write, Err(StoreError::OutOfMemory)));
+            assert_eq!(kv.count("c", &Filter::All).unwrap(), 4);
+            assert_eq!(kv.evicted.load(Ordering::Relaxed), 0);
+        }
+        put(&kv, "k4", 4);
+        assert_eq!(kv.count("c", &Filter::All).unwrap(), 4);
+        assert_eq!(kv.evicted.load(Ordering::Relaxed), 1);
+    }
+
+    /// A keyspace with room for `objects` objects like those [`put`] writes.
+    fn limited(objects: usize, policy: EvictionPolicy) -> Keyspace {
+        let sized = Keyspace::new();
+        put(&sized, "k00", 0);
+        let size = sized.used_memory.load(Ordering::Relaxed);
+        Keyspace::with_memory(MemoryConfig {
+            max_memory: objects * size + size / 2,
+            policy,
+        })
+    }
+
+    #[test]
+    fn small_keyspaces_evict_the_best_victim() {
+        for policy in [EvictionPolicy::AllkeysLru, EvictionPolicy::AllkeysLfu] {
+            let kv = limited(10, policy);
+            for i in 0..10 {
+                put(&kv, &format!("k{:02}", i), i);
+            }
+            for object in kv.collections.get("c").unwrap().iter() {
+                let rank = if object.key() == "k05" { 0 } else { 100 };
+                object.last_access.store(rank, Ordering::Relaxed);
+                object.hits.store(rank, Ordering::Relaxed);
+            }
+            put(&kv, "k10", 10);
+            assert!(kv.get("c", "k05").is_err(), "{:?}", policy);
+            assert_eq!(kv.count("c", &Filter::All).unwrap(), 10);
+        }
+    }
+
+    #[test]
+    fn eviction_sweeps_every_collection() {
+        let kv = limited(50, EvictionPolicy::Random);
+        for i in 0..200 {
+            let name = ["a", "b", "c"][i % 3];
+            kv.upsert(name, &format!("k{:03}", i), |_| {
+                Ok(Object::new(json!({"n": i}), None))
+            })
+            .unwrap();
+        }
+        let counts: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| kv.count(name, &Filter::All).unwrap())
+            .collect();
+        assert!(counts.iter().sum::<usize>() <= 50, "{:?}", counts);
+        assert!(counts.iter().all(|n| *n > 0), "{:?}", counts);
+        assert!(kv.used_memory.load(Ordering::Relaxed) <= kv.memory.max_memory);
+    }
+
+    #[test]
+    fn volatile_ttl_evicts_the_soonest_to_expire() {
+        let kv = limited(4, EvictionPolicy::VolatileTtl);
+        let later = now_millis() + 60_000;
+        for (id, expires_at) in [
+            ("k00", None),
+            ("k01", Some(later + 2)),
+            ("k02", Some(later)),
+        ] {
+            kv.upsert("c", id, |_| Ok(Object::new(json!({"n": 0}), expires_at)))
+                .unwrap();
+        }
+        put(&kv, "k03", 0);
+        put(&kv, "k04", 0);
+        assert!(kv.get("c", "k02").is_err());
+        assert!(kv.get("c", "k01").is_ok());
+        assert!(kv.get("c", "k00").is_ok());
+    }
+
+    #[test]
+    fn rejected_writes_create_no_collection() {
+        let kv = limited(1, EvictionPolicy::NoEviction);
+        let missing = kv.upsert("c", "k00", |_| Err(StoreError::ObjectNotFound));
+        assert!(matches!(missing, Err(StoreError::ObjectNotFound)));
+        let big = json!({"n": "x".repeat(1000)});
+        let too_big = kv.upsert("c", "k00", |_| Ok(Object::new(big.clone(), None)));
+        assert!(matches!(too_big, Err(StoreError::OutOfMemory)));
+        let too_big = kv.insert("c", big, None);
+        assert!(matches!(too_big, Err(StoreError::OutOfMemory)));
+        assert!(!kv.has_collection("c"));
+        assert!(kv.collections().is_empty());
+
+        put(&kv, "k00", 0);
+        assert_eq!(kv.count("c", &Filter::All).unwrap(), 1);
+    }
+
+    #[test]
+    fn writes_the_schema_rejects_leave_nothing_behind() {
+        let kv = Keyspace::new();
+        let schema = Schema::compile(json!({"required": ["n"]})).unwrap();
+        kv.set_schema("c", Some(schema)).unwrap();
+        let rejected = kv.insert("c", json!({}), None);
+        assert!(matches!(rejected, Err(StoreError::SchemaViolation(_))));
+        let rejected = kv.upsert("c", "k00", |_| Ok(Object::new(json!({}), None)));
+        assert!(matches!(rejected, Err(StoreError::SchemaViolation(_))));
+        assert_eq!(kv.count("c", &Filter::All).unwrap(), 0);
+        assert_eq!(kv.collections().len(), 1);
+
+        let removed = kv.set_schema("d", None);
+        assert!(matches!(removed, Err(StoreError::CollectionNotFound)));
+        assert!(!kv.has_collection("d"));
+    }
+}
@@ -0,0 +1,49 @@
+use std::mem::size_of;
+
+use clap::ValueEnum;
+use serde_json::Value;
+
+/// What happens when a write would take the keyspace past its memory limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EvictionPolicy {
+    /// Reject the write.
+    #[default]
+    #[value(name = "noeviction")]
+    NoEviction,
+    /// Evict the objects that were read or written longest ago.
+    AllkeysLru,
+    /// Evict the objects that were read or written the fewest times.
+    AllkeysLfu,
+    /// Evict objects with a TTL, those closest to expiring first.
+    VolatileTtl,
+    /// Evict objects at random.
+    Random,
+}
+
+/// How much memory the keyspace may use and how it makes room.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemoryConfig {
+    /// Limit in bytes for all stored objects; 0 means no limit.
+    pub max_memory: usize,
+    pub policy: EvictionPolicy,
+}
+
+/// Rough number of bytes an object holding `value` takes up, including the bookkeeping
+/// around it but not the bytes of its key.
+pub fn object_size(value: &Value) -> usize {
+    // Key header, entry header and hash table slot.
+    size_of::<String>() + 64 + value_size(value)
+}
+
+fn value_size(value: &Value) -> usize {
+    size_of::<Value>()
+        + match value {
+            Value::Null | Value::Bool(_) | Value::Number(_) => 0,
+            Value::String(s) => s.capacity(),
+            Value::Array(a) => a.iter().map(value_size).sum(),
+            Value::Object(o) => o
+                .iter()
+                .map(|(k, v)| size_of::<String>() + k.capacity() + value_size(v))
+                .sum(),
+        }
+}
@@ -1,34 +1,87 @@
+mod aggregate;
+mod aof;
+mod background;
+mod data_store;
+mod index;
+mod keyspace;
+mod memory;
+mod patch;
+mod pointer;
+mod query;
 mod reader;
+mod schema;
+mod snapshot;
 mod writer;
-mod data_store;
 
-use futures::StreamExt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::message::Response;
+use futures::{SinkExt, StreamExt};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use tracing::info;
+use tracing::{error, info};
+
+pub use aof::{FsyncPolicy, LogConfig};
+pub use background::run_background_tasks;
+pub use keyspace::{Keyspace, LoadMode};
+pub use memory::{EvictionPolicy, MemoryConfig};
+pub use snapshot::Snapshot;
 
-pub async fn handle_connection(stream: TcpStream)->Result<(),Box<dyn std::error::Error>> {
-    let peer_addr = stream.peer_addr().unwrap();
+pub async fn handle_connection(
+    stream: TcpStream,
+    kv: Arc<Keyspace>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let peer_addr = stream.peer_addr()?;
     info!("Accepted connection from {}", peer_addr);
 
     // Wrap the TCP stream with a length-delimited codec.
     let framed = Framed::new(stream, LengthDelimitedCodec::new());
     // Split into writer (sink) and reader (stream) halves.
-    let ( writer_sink, reader_stream) = framed.split();
+    let (writer_sink, reader_stream) = framed.split();
 
     // Create an mpsc channel to pass serialized responses from the reader to the writer.
     let (tx, rx) = mpsc::channel::<common::message::Response>(32);
     let (command_tx, command_rx) = mpsc::channel::<common::message::Command>(32);
-    let reader = reader::Reader::new(reader_stream,command_tx);
+    let reader = reader::Reader::new(reader_stream, command_tx);
     let writer = writer::Writer::new(writer_sink, rx);
-    let data_store = data_store::DataStore::new(tx, command_rx);
+    // Every connection routes its commands into the same process-wide keyspace.
+    let data_store = data_store::DataStore::new(kv, tx, command_rx);
 
-    let writer_handle = tokio::spawn(async move { writer.run().await.unwrap() });
-    let data_handler = tokio::spawn(async move {data_store.run().await.unwrap()});
+    let writer_handle = tokio::spawn(async move {
+        if let Err(e) = writer.run().await {
+            error!("Writer for {} stopped: {}", peer_addr, e);
+        }
+    });
+    let data_handler = tokio::spawn(async move {
+        if let Err(e) = data_store.run().await {
+            error!("Data store for {} stopped: {}", peer_addr, e);
+        }
+    });
     reader.run().await?;
     writer_handle.await?;
     data_handler.await?;
     info!("Connection with {} closed", peer_addr);
     Ok(())
 }
+
+/// Answers a connection the server won't serve with a single error frame.
+///
+/// The read half is drained for a short while afterwards so the client gets to read the
+/// error instead of a connection reset when it sends its first command.
+pub async fn reject_connection(
+    stream: TcpStream,
+    reason: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    let response = Response::to_vec(&Response::ERROR(reason.to_string()))?;
+    framed.send(Bytes::from(response)).await?;
+    framed.close().await?;
+    let _ = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(Ok(_)) = framed.next().await {}
+    })
+    .await;
+    Ok(())
+}
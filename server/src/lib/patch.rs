@@ -0,0 +1,370 @@
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::keyspace::StoreError;
+use crate::pointer::{Pointer, PointerError};
+
+#[derive(Debug, Error)]
+pub enum PatchError {
+    #[error("a JSON Patch must be an array of operations")]
+    NotAnArray,
+
+    #[error("operation {index} is invalid: {reason}")]
+    Invalid { index: usize, reason: String },
+
+    #[error("operation {index} ({op}) failed: {source}")]
+    Failed {
+        index: usize,
+        op: &'static str,
+        source: PointerError,
+    },
+
+    #[error("operation {index} (test) failed: the value at {path} does not match")]
+    TestFailed { index: usize, path: Pointer },
+}
+
+/// How PATCH combines its body with the stored value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchMode {
+    /// JSON Merge Patch (RFC 7396): objects merge recursively and `null` removes a member.
+    Merge,
+    /// JSON Patch (RFC 6902): the body is a list of operations applied in order.
+    JsonPatch,
+    /// Objects gain the body's members, arrays are extended with its elements and scalars
+    /// of the same type are replaced.
+    Append,
+}
+
+/// Applies `patch` to `target` as a JSON Merge Patch (RFC 7396).
+pub fn merge(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let Value::Object(map) = target else {
+        unreachable!("target was just made an object")
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            map.remove(&key);
+        } else {
+            merge(map.entry(key).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Shallowly combines `body` into `target`; both must have the same type.
+pub fn append(target: &mut Value, body: Value) -> Result<(), StoreError> {
+    match (target, body) {
+        (target @ Value::Null, body @ Value::Null)
+        | (target @ Value::Bool(_), body @ Value::Bool(_))
+        | (target @ Value::Number(_), body @ Value::Number(_))
+        | (target @ Value::String(_), body @ Value::String(_)) => *target = body,
+        (Value::Array(a), Value::Array(b)) => a.extend(b),
+        (Value::Object(a), Value::Object(b)) => a.extend(b),
+        _ => return Err(StoreError::TypeMismatch),
+    }
+    Ok(())
+}
+
+/// One RFC 6902 operation, with its pointers still in string form.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+impl Operation {
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::Add { .. } => "add",
+            Operation::Remove { .. } => "remove",
+            Operation::Replace { .. } => "replace",
+            Operation::Move { .. } => "move",
+            Operation::Copy { .. } => "copy",
+            Operation::Test { .. } => "test",
+        }
+    }
+}
+
+/// Applies a JSON Patch (RFC 6902) to `target`.
+///
+/// Operations run in order against `target` itself, so callers that need the patch to be
+/// all-or-nothing apply it to a copy and only keep the copy if this succeeds.
+pub fn apply(target: &mut Value, patch: Value) -> Result<(), PatchError> {
+    let Value::Array(operations) = patch else {
+        return Err(PatchError::NotAnArray);
+    };
+    for (index, operation) in operations.into_iter().enumerate() {
+        let invalid = |reason: String| PatchError::Invalid { index, reason };
+        let operation: Operation =
+            serde_json::from_value(operation).map_err(|e| invalid(e.to_string()))?;
+        let op = operation.name();
+        let pointer = |pointer: &str| Pointer::parse(pointer).map_err(|e| invalid(e.to_string()));
+        let failed = |source| PatchError::Failed { index, op, source };
+        match operation {
+            Operation::Add { path, value } => pointer(&path)?.add(target, value).map_err(failed)?,
+            Operation::Remove { path } => {
+                pointer(&path)?.remove(target).map_err(failed)?;
+            }
+            Operation::Replace { path, value } => {
+                *pointer(&path)?.resolve_mut(target).map_err(failed)? = value;
+            }
+            Operation::Move { from, path } => {
+                let (from, path) = (pointer(&from)?, pointer(&path)?);
+                if from.contains(&path) {
+                    return Err(invalid(format!("{} can't be moved into itself", from)));
+                }
+                if from != path {
+                    let value = from.remove(target).map_err(failed)?;
+                    path.add(target, value).map_err(failed)?;
+                }
+            }
+            Operation::Copy { from, path } => {
+                let from = pointer(&from)?;
+                let value = from
+                    .get(target)
+                    .cloned()
+                    .ok_or_else(|| failed(PointerError::NotFound(from)))?;
+                pointer(&path)?.add(target, value).map_err(failed)?;
+            }
+            Operation::Test { path, value } => {
+                let path = pointer(&path)?;
+                let actual = path
+                    .get(target)
+                    .ok_or_else(|| failed(PointerError::NotFound(path.clone())))?;
+                if !json_eq(actual, &value) {
+                    return Err(PatchError::TestFailed { index, path });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// JSON equality, under which numbers compare by value so `1` equals `1.0`.
+pub fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => a == b,
+            _ => a.as_f64() == b.as_f64(),
+        },
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_eq(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, a)| b.get(key).is_some_and(|b| json_eq(a, b)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn patched(mut target: Value, patch: Value) -> Result<Value, PatchError> {
+        apply(&mut target, patch)?;
+        Ok(target)
+    }
+
+    #[test]
+    fn merge_follows_rfc_7396() {
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (mut target, patch, expected) in cases {
+            merge(&mut target, patch);
+            assert_eq!(target, expected);
+        }
+    }
+
+    #[test]
+    fn append_requires_matching_types() {
+        let mut target = json!({"a": 1});
+        append(&mut target, json!({"b": 2})).unwrap();
+        assert_eq!(target, json!({"a": 1, "b": 2}));
+        let mut target = json!([1]);
+        append(&mut target, json!([2, 3])).unwrap();
+        assert_eq!(target, json!([1, 2, 3]));
+        assert!(matches!(
+            append(&mut target, json!({"a": 1})),
+            Err(StoreError::TypeMismatch)
+        ));
+    }
+
+    #[test]
+    fn add_remove_and_replace() {
+        let doc = json!({"foo": ["bar", "baz"]});
+        assert_eq!(
+            patched(
+                doc.clone(),
+                json!([{"op": "add", "path": "/foo/1", "value": "qux"}])
+            )
+            .unwrap(),
+            json!({"foo": ["bar", "qux", "baz"]})
+        );
+        assert_eq!(
+            patched(
+                doc.clone(),
+                json!([{"op": "add", "path": "/foo/-", "value": "qux"}])
+            )
+            .unwrap(),
+            json!({"foo": ["bar", "baz", "qux"]})
+        );
+        assert_eq!(
+            patched(doc.clone(), json!([{"op": "remove", "path": "/foo/0"}])).unwrap(),
+            json!({"foo": ["baz"]})
+        );
+        assert_eq!(
+            patched(
+                doc.clone(),
+                json!([{"op": "replace", "path": "/foo", "value": 1}])
+            )
+            .unwrap(),
+            json!({"foo": 1})
+        );
+        assert!(matches!(
+            patched(doc, json!([{"op": "replace", "path": "/bar", "value": 1}])),
+            Err(PatchError::Failed { op: "replace", .. })
+        ));
+    }
+
+    #[test]
+    fn move_and_copy() {
+        let doc = json!({"foo": {"bar": "baz", "waldo": "fred"}, "qux": {"corge": "grault"}});
+        assert_eq!(
+            patched(
+                doc.clone(),
+                json!([{"op": "move", "from": "/foo/waldo", "path": "/qux/thud"}])
+            )
+            .unwrap(),
+            json!({"foo": {"bar": "baz"}, "qux": {"corge": "grault", "thud": "fred"}})
+        );
+        assert_eq!(
+            patched(
+                json!([1, 2, 3, 4]),
+                json!([{"op": "move", "from": "/1", "path": "/3"}])
+            )
+            .unwrap(),
+            json!([1, 3, 4, 2])
+        );
+        assert_eq!(
+            patched(
+                doc.clone(),
+                json!([{"op": "copy", "from": "/qux", "path": "/foo/qux"}])
+            )
+            .unwrap()["foo"]["qux"],
+            json!({"corge": "grault"})
+        );
+        assert_eq!(
+            patched(
+                doc.clone(),
+                json!([{"op": "move", "from": "/foo", "path": "/foo"}])
+            )
+            .unwrap(),
+            doc
+        );
+        assert!(matches!(
+            patched(
+                doc.clone(),
+                json!([{"op": "move", "from": "/foo", "path": "/foo/x"}])
+            ),
+            Err(PatchError::Invalid { index: 0, .. })
+        ));
+        assert!(matches!(
+            patched(doc, json!([{"op": "copy", "from": "/nope", "path": "/x"}])),
+            Err(PatchError::Failed { op: "copy", .. })
+        ));
+    }
+
+    #[test]
+    fn test_compares_by_json_value() {
+        let doc = json!({"n": 1, "a": [1, {"b": 2.0}]});
+        assert!(patched(
+            doc.clone(),
+            json!([{"op": "test", "path": "/n", "value": 1.0}])
+        )
+        .is_ok());
+        assert!(patched(
+            doc.clone(),
+            json!([{"op": "test", "path": "/a", "value": [1.0, {"b": 2}]}])
+        )
+        .is_ok());
+        assert!(matches!(
+            patched(
+                doc.clone(),
+                json!([{"op": "test", "path": "/n", "value": "1"}])
+            ),
+            Err(PatchError::TestFailed { index: 0, .. })
+        ));
+        assert!(matches!(
+            patched(
+                doc,
+                json!([{"op": "test", "path": "/missing", "value": null}])
+            ),
+            Err(PatchError::Failed { op: "test", .. })
+        ));
+    }
+
+    #[test]
+    fn reports_the_failing_operation() {
+        let patch = json!([
+            {"op": "add", "path": "/a", "value": 1},
+            {"op": "test", "path": "/a", "value": 2},
+        ]);
+        assert!(matches!(
+            patched(json!({}), patch),
+            Err(PatchError::TestFailed { index: 1, .. })
+        ));
+        assert!(matches!(
+            patched(json!({}), json!([{"op": "frobnicate", "path": "/a"}])),
+            Err(PatchError::Invalid { index: 0, .. })
+        ));
+        assert!(matches!(
+            patched(json!({}), json!({"op": "add"})),
+            Err(PatchError::NotAnArray)
+        ));
+    }
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PointerError {
+    #[error("invalid JSON pointer {0:?}")]
+    Syntax(String),
+
+    #[error("no value at {0}")]
+    NotFound(Pointer),
+
+    #[error("a value already exists at {0}")]
+    Exists(Pointer),
+
+    #[error("{0} is not an object or array")]
+    NotAContainer(Pointer),
+
+    #[error("invalid array index at {0}")]
+    InvalidIndex(Pointer),
+}
+
+/// A JSON Pointer (RFC 6901) into a stored value, kept as its unescaped reference tokens.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pointer(Vec<String>);
+
+impl Pointer {
+    /// The pointer to the whole value.
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    /// Parses the string form, which is empty or starts with `/`.
+    pub fn parse(pointer: &str) -> Result<Self, PointerError> {
+        if pointer.is_empty() {
+            return Ok(Self::root());
+        }
+        let Some(tokens) = pointer.strip_prefix('/') else {
+            return Err(PointerError::Syntax(pointer.to_string()));
+        };
+        tokens
+            .split('/')
+            .map(|token| unescape(token).ok_or_else(|| PointerError::Syntax(pointer.to_string())))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// The pointer made of `tokens`, which are already unescaped.
+    pub fn from_tokens<T: Into<String>>(tokens: impl IntoIterator<Item = T>) -> Self {
+        Self(tokens.into_iter().map(Into::into).collect())
+    }
+
+    /// This pointer with `token` added at the end.
+    pub fn join(&self, token: impl Into<String>) -> Pointer {
+        let mut pointer = self.clone();
+        pointer.0.push(token.into());
+        pointer
+    }
+
+    /// The string form, escaped as [`Pointer::parse`] reads it; empty for the root.
+    pub fn encoded(&self) -> String {
+        self.0
+            .iter()
+            .map(|token| format!("/{}", token.replace('~', "~0").replace('/', "~1")))
+            .collect()
+    }
+
+    /// The unescaped reference tokens, outermost first.
+    pub fn tokens(&self) -> &[String] {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether `other` points somewhere strictly inside the value this one points to.
+    pub fn contains(&self, other: &Pointer) -> bool {
+        other.0.len() > self.0.len() && other.0.starts_with(&self.0)
+    }
+
+    /// The pointer to the value holding this one, and this value's token in it.
+    fn split_last(&self) -> Option<(Pointer, &str)> {
+        let (last, parent) = self.0.split_last()?;
+        Some((Pointer(parent.to_vec()), last))
+    }
+
+    pub fn get<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.0.iter().try_fold(value, |value, token| match value {
+            Value::Object(map) => map.get(token),
+            Value::Array(array) => array.get(index(token)?),
+            _ => None,
+        })
+    }
+
+    pub fn get_mut<'a>(&self, value: &'a mut Value) -> Option<&'a mut Value> {
+        self.0.iter().try_fold(value, |value, token| match value {
+            Value::Object(map) => map.get_mut(token),
+            Value::Array(array) => array.get_mut(index(token)?),
+            _ => None,
+        })
+    }
+
+    /// Like [`Pointer::get_mut`], but says why nothing was found.
+    pub fn resolve_mut<'a>(&self, value: &'a mut Value) -> Result<&'a mut Value, PointerError> {
+        self.get_mut(value)
+            .ok_or_else(|| PointerError::NotFound(self.clone()))
+    }
+
+    /// The value at this pointer, created as `null` if it is missing.
+    ///
+    /// Missing or `null` parents become objects on the way down; arrays only grow at `-`.
+    pub fn get_or_create<'a>(&self, value: &'a mut Value) -> Result<&'a mut Value, PointerError> {
+        let mut current = value;
+        for (depth, token) in self.0.iter().enumerate() {
+            if current.is_null() {
+                *current = Value::Object(Map::new());
+            }
+            current = match current {
+                Value::Object(map) => map.entry(token.clone()).or_insert(Value::Null),
+                Value::Array(array) => {
+                    let i = if token == "-" {
+                        array.push(Value::Null);
+                        array.len() - 1
+                    } else {
+                        index(token)
+                            .filter(|i| *i < array.len())
+                            .ok_or_else(|| PointerError::InvalidIndex(self.prefix(depth + 1)))?
+                    };
+                    &mut array[i]
+                }
+                _ => return Err(PointerError::NotAContainer(self.prefix(depth))),
+            };
+        }
+        Ok(current)
+    }
+
+    /// The pointer made of the first `len` tokens of this one.
+    fn prefix(&self, len: usize) -> Pointer {
+        Pointer(self.0[..len].to_vec())
+    }
+
+    /// Stores `new` at this pointer and returns the value it replaced.
+    ///
+    /// The parent must already exist. A member is created if the parent is an object; in an
+    /// array, `-` or the current length appends while a lower index replaces that element.
+    pub fn set(&self, value: &mut Value, new: Value) -> Result<Option<Value>, PointerError> {
+        let Some((parent, token)) = self.split_last() else {
+            return Ok(Some(std::mem::replace(value, new)));
+        };
+        match parent.resolve_mut(value)? {
+            Value::Object(map) => Ok(map.insert(token.to_string(), new)),
+            Value::Array(array) => match self.array_index(token, array.len())? {
+                i if i == array.len() => {
+                    array.push(new);
+                    Ok(None)
+                }
+                i => Ok(Some(std::mem::replace(&mut array[i], new))),
+            },
+            _ => Err(PointerError::NotAContainer(parent)),
+        }
+    }
+
+    /// Like [`Pointer::set`], but an array index shifts later elements up instead of
+    /// replacing one, as the `add` operation of RFC 6902 does.
+    pub fn add(&self, value: &mut Value, new: Value) -> Result<(), PointerError> {
+        let Some((parent, token)) = self.split_last() else {
+            *value = new;
+            return Ok(());
+        };
+        match parent.resolve_mut(value)? {
+            Value::Object(map) => {
+                map.insert(token.to_string(), new);
+            }
+            Value::Array(array) => {
+                let i = self.array_index(token, array.len())?;
+                array.insert(i, new);
+            }
+            _ => return Err(PointerError::NotAContainer(parent)),
+        }
+        Ok(())
+    }
+
+    /// Takes the value at this pointer out of its parent.
+    ///
+    /// The whole value can't be removed this way; callers delete the object instead.
+    pub fn remove(&self, value: &mut Value) -> Result<Value, PointerError> {
+        let Some((parent, token)) = self.split_last() else {
+            return Err(PointerError::NotFound(self.clone()));
+        };
+        match parent.resolve_mut(value)? {
+            Value::Object(map) => map
+                .remove(token)
+                .ok_or_else(|| PointerError::NotFound(self.clone())),
+            Value::Array(array) => match index(token).filter(|i| *i < array.len()) {
+                Some(i) => Ok(array.remove(i)),
+                None => Err(PointerError::NotFound(self.clone())),
+            },
+            _ => Err(PointerError::NotAContainer(parent)),
+        }
+    }
+
+    /// Reads `token` as a position to insert at in an array of `len` elements.
+    fn array_index(&self, token: &str, len: usize) -> Result<usize, PointerError> {
+        if token == "-" {
+            return Ok(len);
+        }
+        index(token)
+            .filter(|i| *i <= len)
+            .ok_or_else(|| PointerError::InvalidIndex(self.clone()))
+    }
+}
+
+impl fmt::Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str("\"\"");
+        }
+        f.write_str(&self.encoded())
+    }
+}
+
+/// Decodes `~1` to `/` and `~0` to `~`; any other `~` is an error.
+pub fn unescape(token: &str) -> Option<String> {
+    let mut out = String::with_capacity(token.len());
+    let mut chars = token.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '~' => match chars.next()? {
+                '0' => out.push('~'),
+                '1' => out.push('/'),
+                _ => return None,
+            },
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+/// An array index token: digits without leading zeros.
+fn index(token: &str) -> Option<usize> {
+    if token.is_empty()
+        || !token.bytes().all(|b| b.is_ascii_digit())
+        || (token.len() > 1 && token.starts_with('0'))
+    {
+        return None;
+    }
+    token.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn pointer(s: &str) -> Pointer {
+        Pointer::parse(s).unwrap()
+    }
+
+    #[test]
+    fn resolves_the_rfc_6901_examples() {
+        let doc = json!({
+            "foo": ["bar", "baz"],
+            "": 0,
+            "a/b": 1,
+            "c%d": 2,
+            "e^f": 3,
+            "g|h": 4,
+            "i\\j": 5,
+            "k\"l": 6,
+            " ": 7,
+            "m~n": 8
+        });
+        let cases = [
+            ("", doc.clone()),
+            ("/foo", json!(["bar", "baz"])),
+            ("/foo/0", json!("bar")),
+            ("/", json!(0)),
+            ("/a~1b", json!(1)),
+            ("/c%d", json!(2)),
+            ("/e^f", json!(3)),
+            ("/g|h", json!(4)),
+            ("/i\\j", json!(5)),
+            ("/k\"l", json!(6)),
+            ("/ ", json!(7)),
+            ("/m~0n", json!(8)),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(pointer(path).get(&doc), Some(&expected), "{path}");
+        }
+    }
+
+    #[test]
+    fn escapes_round_trip() {
+        let p = Pointer::from_tokens(["a/b", "m~n", "~1"]);
+        assert_eq!(p.encoded(), "/a~1b/m~0n/~01");
+        assert_eq!(pointer(&p.encoded()), p);
+        assert_eq!(Pointer::root().encoded(), "");
+        assert_eq!(Pointer::root().to_string(), "\"\"");
+    }
+
+    #[test]
+    fn rejects_bad_syntax() {
+        assert!(Pointer::parse("foo").is_err());
+        assert!(Pointer::parse("/a~2").is_err());
+        assert!(Pointer::parse("/a~").is_err());
+    }
+
+    #[test]
+    fn array_indexes_have_no_leading_zeros() {
+        let doc = json!([10, 11]);
+        assert_eq!(pointer("/1").get(&doc), Some(&json!(11)));
+        assert_eq!(pointer("/01").get(&doc), None);
+        assert_eq!(pointer("/-").get(&doc), None);
+        assert_eq!(pointer("/2").get(&doc), None);
+    }
+
+    #[test]
+    fn set_replaces_array_elements_and_add_inserts() {
+        let mut doc = json!({"a": [1, 2]});
+        assert_eq!(
+            pointer("/a/0").set(&mut doc, json!(0)).unwrap(),
+            Some(json!(1))
+        );
+        assert_eq!(pointer("/a/-").set(&mut doc, json!(3)).unwrap(), None);
+        assert_eq!(doc, json!({"a": [0, 2, 3]}));
+        pointer("/a/1").add(&mut doc, json!(1)).unwrap();
+        assert_eq!(doc, json!({"a": [0, 1, 2, 3]}));
+        assert!(matches!(
+            pointer("/a/9").add(&mut doc, json!(9)),
+            Err(PointerError::InvalidIndex(_))
+        ));
+        assert!(matches!(
+            pointer("/b/c").set(&mut doc, json!(1)),
+            Err(PointerError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn get_or_create_builds_missing_parents() {
+        let mut doc = json!(null);
+        *pointer("/a/b").get_or_create(&mut doc).unwrap() = json!(1);
+        assert_eq!(doc, json!({"a": {"b": 1}}));
+        assert!(matches!(
+            pointer("/a/b/c").get_or_create(&mut doc),
+            Err(PointerError::NotAContainer(_))
+        ));
+    }
+
+    #[test]
+    fn remove_takes_values_out() {
+        let mut doc = json!({"a": [1, 2], "b": 3});
+        assert_eq!(pointer("/a/0").remove(&mut doc).unwrap(), json!(1));
+        assert_eq!(pointer("/b").remove(&mut doc).unwrap(), json!(3));
+        assert_eq!(doc, json!({"a": [2]}));
+        assert!(pointer("/b").remove(&mut doc).is_err());
+        assert!(Pointer::root().remove(&mut doc).is_err());
+    }
+
+    #[test]
+    fn contains_is_strict() {
+        assert!(pointer("/a").contains(&pointer("/a/b")));
+        assert!(!pointer("/a").contains(&pointer("/a")));
+        assert!(!pointer("/a").contains(&pointer("/ab")));
+        assert!(Pointer::root().contains(&pointer("/a")));
+    }
+}
@@ -0,0 +1,605 @@
+use std::cmp::Ordering;
+
+use regex::Regex;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::patch::json_eq;
+use crate::pointer::Pointer;
+
+/// A query that can't be used, with the place in the headers where the problem is.
+#[derive(Debug, Error)]
+#[error("invalid query at {at}: {reason}")]
+pub struct QueryError {
+    pub at: Pointer,
+    pub reason: String,
+}
+
+impl QueryError {
+    pub fn new(at: &Pointer, reason: impl Into<String>) -> Self {
+        Self {
+            at: at.clone(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// A condition on stored values, parsed from the `where` header of a read.
+///
+/// The header maps field paths to conditions, all of which must hold. A path is either a
+/// JSON Pointer such as `/address/city` or dotted, as in `address.city`. A condition is a
+/// plain value, which the field must equal, or an object of operators such as
+/// `{"$gt": 30, "$lt": 40}`. `$and`, `$or` and `$not` combine whole filters.
+#[derive(Debug, Default)]
+pub enum Filter {
+    /// Every filter holds; with none, everything matches.
+    #[default]
+    All,
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Field {
+        path: Pointer,
+        condition: Condition,
+    },
+}
+
+#[derive(Debug)]
+pub enum Condition {
+    Eq(Value),
+    Ne(Value),
+    Gt(Value),
+    Gte(Value),
+    Lt(Value),
+    Lte(Value),
+    In(Vec<Value>),
+    Nin(Vec<Value>),
+    Exists(bool),
+    Regex(Regex),
+}
+
+impl Filter {
+    /// Reads the `where` header, if there is one.
+    pub fn from_headers(headers: &Option<Map<String, Value>>) -> Result<Self, QueryError> {
+        match headers.as_ref().and_then(|h| h.get("where")) {
+            None => Ok(Filter::All),
+            Some(query) => Self::parse(query, &Pointer::root().join("where")),
+        }
+    }
+
+    /// Parses the filter in `query`, which sits at `at` in the headers.
+    fn parse(query: &Value, at: &Pointer) -> Result<Self, QueryError> {
+        let Value::Object(clauses) = query else {
+            return Err(QueryError::new(at, "expected an object"));
+        };
+        let mut filters = Vec::with_capacity(clauses.len());
+        for (key, clause) in clauses {
+            let at = at.join(key.as_str());
+            match key.as_str() {
+                "$and" | "$or" => {
+                    let Value::Array(clauses) = clause else {
+                        return Err(QueryError::new(&at, "expected an array of filters"));
+                    };
+                    let clauses = clauses
+                        .iter()
+                        .enumerate()
+                        .map(|(i, clause)| Self::parse(clause, &at.join(i.to_string())))
+                        .collect::<Result<_, _>>()?;
+                    filters.push(match key.as_str() {
+                        "$and" => Filter::And(clauses),
+                        _ => Filter::Or(clauses),
+                    });
+                }
+                "$not" => filters.push(Filter::Not(Box::new(Self::parse(clause, &at)?))),
+                key if key.starts_with('$') => {
+                    return Err(QueryError::new(&at, format!("unknown operator {}", key)));
+                }
+                key => {
+                    let path = field_path(key).map_err(|reason| QueryError::new(&at, reason))?;
+                    for condition in Condition::parse(clause, &at)? {
+                        filters.push(Filter::Field {
+                            path: path.clone(),
+                            condition,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(match filters.len() {
+            0 => Filter::All,
+            1 => filters.pop().expect("there is one filter"),
+            _ => Filter::And(filters),
+        })
+    }
+
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::And(filters) => filters.iter().all(|f| f.matches(value)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(value)),
+            Filter::Not(filter) => !filter.matches(value),
+            Filter::Field { path, condition } => condition.matches(path.get(value)),
+        }
+    }
+}
+
+impl Condition {
+    /// Parses the conditions on one field: a plain value, or an object of operators.
+    fn parse(clause: &Value, at: &Pointer) -> Result<Vec<Self>, QueryError> {
+        let operators = match clause {
+            Value::Object(map) if !map.is_empty() && map.keys().all(|k| k.starts_with('$')) => map,
+            value => return Ok(vec![Condition::Eq(value.clone())]),
+        };
+        operators
+            .iter()
+            .map(|(operator, operand)| {
+                let at = at.join(operator.as_str());
+                let comparable = || match operand {
+                    Value::Number(_) | Value::String(_) => Ok(operand.clone()),
+                    _ => Err(QueryError::new(&at, "expected a number or a string")),
+                };
+                let list = || match operand {
+                    Value::Array(values) => Ok(values.clone()),
+                    _ => Err(QueryError::new(&at, "expected an array")),
+                };
+                Ok(match operator.as_str() {
+                    "$eq" => Condition::Eq(operand.clone()),
+                    "$ne" => Condition::Ne(operand.clone()),
+                    "$gt" => Condition::Gt(comparable()?),
+                    "$gte" => Condition::Gte(comparable()?),
+                    "$lt" => Condition::Lt(comparable()?),
+                    "$lte" => Condition::Lte(comparable()?),
+                    "$in" => Condition::In(list()?),
+                    "$nin" => Condition::Nin(list()?),
+                    "$exists" => match operand {
+                        Value::Bool(exists) => Condition::Exists(*exists),
+                        _ => return Err(QueryError::new(&at, "expected true or false")),
+                    },
+                    "$regex" => match operand {
+                        Value::String(pattern) => Condition::Regex(
+                            Regex::new(pattern).map_err(|e| QueryError::new(&at, e.to_string()))?,
+                        ),
+                        _ => return Err(QueryError::new(&at, "expected a string")),
+                    },
+                    _ => {
+                        return Err(QueryError::new(
+                            &at,
+                            format!("unknown operator {}", operator),
+                        ))
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Whether a field holds; `field` is `None` when the value has no such field.
+    fn matches(&self, field: Option<&Value>) -> bool {
+        let ordered = |operand: &Value, wanted: fn(Ordering) -> bool| {
+            field.and_then(|f| compare(f, operand)).is_some_and(wanted)
+        };
+        match self {
+            Condition::Eq(operand) => equals(field, operand),
+            Condition::Ne(operand) => !equals(field, operand),
+            Condition::Gt(operand) => ordered(operand, Ordering::is_gt),
+            Condition::Gte(operand) => ordered(operand, Ordering::is_ge),
+            Condition::Lt(operand) => ordered(operand, Ordering::is_lt),
+            Condition::Lte(operand) => ordered(operand, Ordering::is_le),
+            Condition::In(operands) => operands.iter().any(|o| equals(field, o)),
+            Condition::Nin(operands) => !operands.iter().any(|o| equals(field, o)),
+            Condition::Exists(exists) => field.is_some() == *exists,
+            Condition::Regex(regex) => field
+                .and_then(Value::as_str)
+                .is_some_and(|s| regex.is_match(s)),
+        }
+    }
+}
+
+/// A missing field equals `null`, so `{"field": null}` also finds objects without it.
+fn equals(field: Option<&Value>, operand: &Value) -> bool {
+    field.map_or(operand.is_null(), |field| json_eq(field, operand))
+}
+
+/// Which parts of stored values a read returns, from its `fields` header.
+///
+/// The header is an array of field paths, written as in a filter. Listed fields are kept
+/// and everything else is left out, or, when every path starts with `-`, the listed fields
+/// are left out and everything else is kept. Arrays on the way are projected element by
+/// element, so `tags.name` keeps the `name` of every tag.
+#[derive(Debug, Default)]
+pub enum Projection {
+    #[default]
+    All,
+    Include(Vec<Pointer>),
+    Exclude(Vec<Pointer>),
+}
+
+impl Projection {
+    pub fn from_headers(headers: &Option<Map<String, Value>>) -> Result<Self, QueryError> {
+        let Some(fields) = headers.as_ref().and_then(|h| h.get("fields")) else {
+            return Ok(Projection::All);
+        };
+        let at = Pointer::root().join("fields");
+        let Value::Array(fields) = fields else {
+            return Err(QueryError::new(&at, "expected an array of field paths"));
+        };
+        if fields.is_empty() {
+            return Err(QueryError::new(&at, "expected at least one field path"));
+        }
+        let mut included = Vec::new();
+        let mut excluded = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            let at = at.join(i.to_string());
+            let Value::String(field) = field else {
+                return Err(QueryError::new(&at, "expected a string"));
+            };
+            let (paths, field) = match field.strip_prefix('-') {
+                Some(field) => (&mut excluded, field),
+                None => (&mut included, field.as_str()),
+            };
+            paths.push(field_path(field).map_err(|reason| QueryError::new(&at, reason))?);
+            if !included.is_empty() && !excluded.is_empty() {
+                return Err(QueryError::new(
+                    &at,
+                    "can't both include and exclude fields",
+                ));
+            }
+        }
+        Ok(if excluded.is_empty() {
+            Projection::Include(included)
+        } else {
+            Projection::Exclude(excluded)
+        })
+    }
+
+    /// Copies the parts of `value` this projection keeps.
+    pub fn apply(&self, value: &Value) -> Value {
+        match self {
+            Projection::All => value.clone(),
+            Projection::Include(paths) => include(value, &tokens(paths)).unwrap_or(Value::Null),
+            Projection::Exclude(paths) => exclude(value, &tokens(paths)).unwrap_or(Value::Null),
+        }
+    }
+}
+
+fn tokens(paths: &[Pointer]) -> Vec<&[String]> {
+    paths.iter().map(Pointer::tokens).collect()
+}
+
+/// The parts of `value` at `paths`, or `None` if there are none.
+fn include(value: &Value, paths: &[&[String]]) -> Option<Value> {
+    if paths.iter().any(|path| path.is_empty()) {
+        return Some(value.clone());
+    }
+    match value {
+        Value::Object(map) => Some(Value::Object(
+            map.iter()
+                .filter_map(|(key, value)| {
+                    let paths = descend(paths, key);
+                    if paths.is_empty() {
+                        return None;
+                    }
+                    Some((key.clone(), include(value, &paths)?))
+                })
+                .collect(),
+        )),
+        Value::Array(array) => Some(Value::Array(
+            array
+                .iter()
+                .filter_map(|value| include(value, paths))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// `value` without the parts at `paths`, or `None` if all of it is left out.
+fn exclude(value: &Value, paths: &[&[String]]) -> Option<Value> {
+    if paths.iter().any(|path| path.is_empty()) {
+        return None;
+    }
+    match value {
+        Value::Object(map) => Some(Value::Object(
+            map.iter()
+                .filter_map(|(key, value)| {
+                    let paths = descend(paths, key);
+                    Some((key.clone(), exclude(value, &paths)?))
+                })
+                .collect(),
+        )),
+        Value::Array(array) => Some(Value::Array(
+            array
+                .iter()
+                .filter_map(|value| exclude(value, paths))
+                .collect(),
+        )),
+        value => Some(value.clone()),
+    }
+}
+
+/// What is left of the paths that go through the member `key`.
+fn descend<'a>(paths: &[&'a [String]], key: &str) -> Vec<&'a [String]> {
+    paths
+        .iter()
+        .filter_map(|path| match path.split_first() {
+            Some((first, rest)) if first == key => Some(rest),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The order a read returns values in, from its `sort` header.
+///
+/// The header is a field path or an array of them, the first one deciding and later ones
+/// breaking ties. A path starting with `-` sorts from high to low. Values of different
+/// types sort as missing, `null`, booleans, numbers, strings, arrays, then objects.
+#[derive(Debug)]
+pub struct Sort(Vec<SortKey>);
+
+#[derive(Debug)]
+struct SortKey {
+    path: Pointer,
+    descending: bool,
+}
+
+impl Sort {
+    pub fn from_headers(headers: &Option<Map<String, Value>>) -> Result<Option<Self>, QueryError> {
+        let Some(sort) = headers.as_ref().and_then(|h| h.get("sort")) else {
+            return Ok(None);
+        };
+        let at = Pointer::root().join("sort");
+        let keys = match sort {
+            Value::String(_) => vec![(at.clone(), sort)],
+            Value::Array(keys) if !keys.is_empty() => keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| (at.join(i.to_string()), key))
+                .collect(),
+            _ => {
+                return Err(QueryError::new(
+                    &at,
+                    "expected a field path or an array of them",
+                ))
+            }
+        };
+        keys.into_iter()
+            .map(|(at, key)| {
+                let Value::String(key) = key else {
+                    return Err(QueryError::new(&at, "expected a string"));
+                };
+                let (descending, key) = match key.strip_prefix('-') {
+                    Some(key) => (true, key),
+                    None => (false, key.as_str()),
+                };
+                let path = field_path(key).map_err(|reason| QueryError::new(&at, reason))?;
+                Ok(SortKey { path, descending })
+            })
+            .collect::<Result<_, _>>()
+            .map(|keys| Some(Sort(keys)))
+    }
+
+    /// Copies the fields of `value` that decide where it goes.
+    pub fn keys(&self, value: &Value) -> Vec<Option<Value>> {
+        self.0
+            .iter()
+            .map(|key| key.path.get(value).cloned())
+            .collect()
+    }
+
+    /// Orders two values by the fields [`Sort::keys`] took from them.
+    pub fn compare(&self, a: &[Option<Value>], b: &[Option<Value>]) -> Ordering {
+        self.0
+            .iter()
+            .zip(a.iter().zip(b))
+            .map(|(key, (a, b))| {
+                let ordering = order(a.as_ref(), b.as_ref());
+                if key.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    /// Sorts `values` in place; equal values keep their order.
+    pub fn sort(&self, values: &mut Vec<Value>) {
+        let mut keyed: Vec<_> = values.drain(..).map(|v| (self.keys(&v), v)).collect();
+        keyed.sort_by(|(a, _), (b, _)| self.compare(a, b));
+        values.extend(keyed.into_iter().map(|(_, v)| v));
+    }
+}
+
+/// A total order over fields, `None` standing for a missing one.
+pub fn order(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    fn rank(value: Option<&Value>) -> u8 {
+        match value {
+            None => 0,
+            Some(Value::Null) => 1,
+            Some(Value::Bool(_)) => 2,
+            Some(Value::Number(_)) => 3,
+            Some(Value::String(_)) => 4,
+            Some(Value::Array(_)) => 5,
+            Some(Value::Object(_)) => 6,
+        }
+    }
+    match (a, b) {
+        (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+        (Some(Value::Array(a)), Some(Value::Array(b))) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| order(Some(a), Some(b)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Some(a), Some(b)) if rank(Some(a)) == rank(Some(b)) => {
+            compare(a, b).unwrap_or(Ordering::Equal)
+        }
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Orders two numbers or two strings; values of other or different types don't compare.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Reads a field path, given as a JSON Pointer or with dots between the fields.
+///
+/// An empty path is the whole value.
+pub fn field_path(path: &str) -> Result<Pointer, String> {
+    if path.is_empty() || path.starts_with('/') {
+        Pointer::parse(path).map_err(|e| e.to_string())
+    } else {
+        Ok(Pointer::from_tokens(path.split('.')))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn headers(headers: Value) -> Option<Map<String, Value>> {
+        match headers {
+            Value::Object(headers) => Some(headers),
+            _ => panic!("headers must be an object"),
+        }
+    }
+
+    fn filter(query: Value) -> Filter {
+        Filter::from_headers(&headers(json!({ "where": query }))).unwrap()
+    }
+
+    fn filter_error(query: Value) -> String {
+        Filter::from_headers(&headers(json!({ "where": query })))
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn no_where_header_matches_everything() {
+        assert!(matches!(Filter::from_headers(&None), Ok(Filter::All)));
+        assert!(filter(json!({})).matches(&json!(1)));
+    }
+
+    #[test]
+    fn plain_values_and_operators() {
+        let doc = json!({"age": 35, "name": "Ann", "address": {"city": "Oslo"}, "tags": ["a"]});
+        assert!(filter(json!({"age": 35.0})).matches(&doc));
+        assert!(filter(json!({"address.city": "Oslo"})).matches(&doc));
+        assert!(filter(json!({"/address/city": "Oslo"})).matches(&doc));
+        assert!(filter(json!({"tags": ["a"]})).matches(&doc));
+        assert!(filter(json!({"age": {"$gt": 30, "$lt": 40}})).matches(&doc));
+        assert!(!filter(json!({"age": {"$gte": 36}})).matches(&doc));
+        assert!(filter(json!({"name": {"$in": ["Bob", "Ann"]}})).matches(&doc));
+        assert!(filter(json!({"name": {"$nin": ["Bob"]}})).matches(&doc));
+        assert!(filter(json!({"name": {"$regex": "^A"}})).matches(&doc));
+        assert!(filter(json!({"name": {"$ne": "Bob"}})).matches(&doc));
+        // Numbers and strings don't compare with each other.
+        assert!(!filter(json!({"name": {"$gt": 1}})).matches(&doc));
+    }
+
+    #[test]
+    fn missing_fields_equal_null() {
+        let doc = json!({"a": 1});
+        assert!(filter(json!({"b": null})).matches(&doc));
+        assert!(filter(json!({"b": {"$exists": false}})).matches(&doc));
+        assert!(!filter(json!({"a": {"$exists": false}})).matches(&doc));
+        assert!(!filter(json!({"b": {"$gt": 0}})).matches(&doc));
+    }
+
+    #[test]
+    fn combinators() {
+        let doc = json!({"a": 1, "b": 2});
+        assert!(filter(json!({"$or": [{"a": 2}, {"b": 2}]})).matches(&doc));
+        assert!(!filter(json!({"$and": [{"a": 1}, {"b": 1}]})).matches(&doc));
+        assert!(filter(json!({"$not": {"a": 2}})).matches(&doc));
+        assert!(!filter(json!({"a": 1, "b": 3})).matches(&doc));
+    }
+
+    #[test]
+    fn errors_point_into_the_header() {
+        assert_eq!(
+            filter_error(json!({"age": {"$gt": true}})),
+            "invalid query at /where/age/$gt: expected a number or a string"
+        );
+        assert_eq!(
+            filter_error(json!({"$or": [{"a": {"$bogus": 1}}]})),
+            "invalid query at /where/$or/0/a/$bogus: unknown operator $bogus"
+        );
+        assert_eq!(
+            filter_error(json!({"name": {"$regex": "("}}))
+                .split(':')
+                .next(),
+            Some("invalid query at /where/name/$regex")
+        );
+        assert_eq!(
+            filter_error(json!([])),
+            "invalid query at /where: expected an object"
+        );
+    }
+
+    #[test]
+    fn projections_include_or_exclude() {
+        let doc = json!({"a": 1, "b": {"c": 2, "d": 3}, "tags": [{"name": "x", "id": 1}]});
+        let project = |fields: Value| {
+            Projection::from_headers(&headers(json!({ "fields": fields })))
+                .unwrap()
+                .apply(&doc)
+        };
+        assert_eq!(project(json!(["a", "b.c"])), json!({"a": 1, "b": {"c": 2}}));
+        assert_eq!(
+            project(json!(["tags.name"])),
+            json!({"tags": [{"name": "x"}]})
+        );
+        assert_eq!(project(json!(["-b", "-tags"])), json!({"a": 1}));
+        assert_eq!(project(json!(["missing"])), json!({}));
+        assert!(Projection::from_headers(&headers(json!({"fields": ["a", "-b"]}))).is_err());
+        assert!(Projection::from_headers(&headers(json!({"fields": []}))).is_err());
+    }
+
+    #[test]
+    fn sorts_by_several_keys_across_types() {
+        let sort = Sort::from_headers(&headers(json!({"sort": ["-a", "b"]})))
+            .unwrap()
+            .unwrap();
+        let mut values = vec![
+            json!({"a": 1, "b": 2}),
+            json!({"a": "x"}),
+            json!({"b": 0}),
+            json!({"a": 1, "b": 1}),
+            json!({"a": null}),
+            json!({"a": 2.5}),
+        ];
+        sort.sort(&mut values);
+        assert_eq!(
+            values,
+            vec![
+                json!({"a": "x"}),
+                json!({"a": 2.5}),
+                json!({"a": 1, "b": 1}),
+                json!({"a": 1, "b": 2}),
+                json!({"a": null}),
+                json!({"b": 0}),
+            ]
+        );
+        assert!(Sort::from_headers(&None).unwrap().is_none());
+        assert!(Sort::from_headers(&headers(json!({"sort": []}))).is_err());
+    }
+
+    #[test]
+    fn field_paths_are_pointers_or_dotted() {
+        assert_eq!(field_path("a.b").unwrap(), Pointer::from_tokens(["a", "b"]));
+        assert_eq!(field_path("/a~1b").unwrap(), Pointer::from_tokens(["a/b"]));
+        assert!(field_path("").unwrap().is_root());
+        assert!(field_path("/a~2").is_err());
+    }
+}
@@ -1,27 +1,24 @@
 use bytes::BytesMut;
-use common::message::{Command, Response};
-use futures::{stream::SplitStream, Stream, StreamExt};
+use common::message::Command;
+use futures::{stream::SplitStream, StreamExt};
 use thiserror::Error;
 use tokio::{
     net::TcpStream,
     sync::mpsc::{error::SendError, Sender},
 };
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use tracing::{error, info};
+use tracing::error;
 
 #[derive(Debug, Error)]
 pub enum ReaderError {
     #[error("Parse error occurred")]
-    ParseError,
+    Parse,
 
     #[error("Read Error")]
-    ReadError,
+    Read,
 
     #[error("send to data task error {0}")]
-    SendToDataTaskError(SendError<Command>),
-
-    #[error("invalid uri {0}")]
-    InvalidURI(String),
+    SendToDataTask(SendError<Command>),
 }
 
 pub struct Reader {
@@ -45,7 +42,7 @@ impl Reader {
                 }
                 Err(e) => {
                     eprintln!("Failed to read from socket: {}", e);
-                    return Err(ReaderError::ReadError);
+                    return Err(ReaderError::Read);
                 }
             }
         }
@@ -54,10 +51,10 @@ impl Reader {
 
     async fn process_message(&self, msg: BytesMut) -> Result<(), ReaderError> {
         let command =
-            common::message::Command::from_slice(&msg).map_err(|_| ReaderError::ParseError)?;
+            common::message::Command::from_slice(&msg).map_err(|_| ReaderError::Parse)?;
         if let Err(e) = self.command_tx.send(command).await {
             error!("Error forwarding command: {}", e);
-            return Err(ReaderError::SendToDataTaskError(e));
+            return Err(ReaderError::SendToDataTask(e));
         }
         Ok(())
     }
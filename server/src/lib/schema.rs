@@ -0,0 +1,773 @@
+use std::fmt;
+
+use regex::Regex;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::patch::json_eq;
+use crate::pointer::Pointer;
+
+/// A schema that can't be used, with the place in it where the problem is.
+#[derive(Debug, Error)]
+#[error("invalid schema at {at}: {reason}")]
+pub struct SchemaError {
+    pub at: Pointer,
+    pub reason: String,
+}
+
+impl SchemaError {
+    fn new(at: &Pointer, reason: impl Into<String>) -> Self {
+        Self {
+            at: at.clone(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// One way a value fails its schema, and where in the value.
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub path: Pointer,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Every violation found in one value.
+#[derive(Debug, Clone)]
+pub struct Violations(pub Vec<Violation>);
+
+impl fmt::Display for Violations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, violation) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a dry-run validation of a collection found.
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub checked: usize,
+    /// The key and violations of every object that fails, in key order.
+    pub invalid: Vec<(String, Vec<Violation>)>,
+}
+
+/// A JSON Schema that objects written to a collection must match.
+///
+/// This is the part of draft 2020-12 that needs no references: `type`, `enum`, `const`,
+/// the numeric, string, array and object assertions, `properties` and friends,
+/// `allOf`/`anyOf`/`oneOf`/`not` and `if`/`then`/`else`. Annotations such as `title` and
+/// `format` are accepted and ignored; keywords that need more, such as `$ref`, are
+/// rejected rather than silently skipped.
+#[derive(Debug)]
+pub struct Schema {
+    source: Value,
+    root: Node,
+}
+
+impl Schema {
+    pub fn compile(source: Value) -> Result<Self, SchemaError> {
+        let root = Node::compile(&source, &Pointer::root())?;
+        Ok(Self { source, root })
+    }
+
+    /// The schema as it was given.
+    pub fn source(&self) -> &Value {
+        &self.source
+    }
+
+    /// Every violation in `value`; none means it matches.
+    pub fn validate(&self, value: &Value) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        self.root.check(value, &Pointer::root(), &mut violations);
+        violations
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    /// `true` allows anything and `false` nothing.
+    Bool(bool),
+    Rules(Vec<Rule>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Null,
+    Boolean,
+    Integer,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+#[derive(Debug)]
+enum Rule {
+    Type(Vec<Type>),
+    Enum(Vec<Value>),
+    Const(Value),
+    Minimum(f64),
+    Maximum(f64),
+    ExclusiveMinimum(f64),
+    ExclusiveMaximum(f64),
+    MultipleOf(f64),
+    MinLength(usize),
+    MaxLength(usize),
+    Pattern(Regex),
+    MinItems(usize),
+    MaxItems(usize),
+    UniqueItems,
+    PrefixItems(Vec<Node>),
+    /// Applies to the items after the first `skip`, which `prefixItems` covers.
+    Items {
+        skip: usize,
+        schema: Box<Node>,
+    },
+    Contains(Box<Node>),
+    MinProperties(usize),
+    MaxProperties(usize),
+    Required(Vec<String>),
+    DependentRequired(Vec<(String, Vec<String>)>),
+    Properties(Vec<(String, Node)>),
+    PatternProperties(Vec<(Regex, Node)>),
+    /// Applies to members that neither `properties` nor `patternProperties` cover.
+    AdditionalProperties {
+        known: Vec<String>,
+        patterns: Vec<Regex>,
+        schema: Box<Node>,
+    },
+    PropertyNames(Box<Node>),
+    AllOf(Vec<Node>),
+    AnyOf(Vec<Node>),
+    OneOf(Vec<Node>),
+    Not(Box<Node>),
+    If {
+        condition: Box<Node>,
+        then: Option<Box<Node>>,
+        otherwise: Option<Box<Node>>,
+    },
+}
+
+/// Keywords that would need references or annotation tracking.
+const UNSUPPORTED: &[&str] = &[
+    "$ref",
+    "$dynamicRef",
+    "$recursiveRef",
+    "dependentSchemas",
+    "unevaluatedItems",
+    "unevaluatedProperties",
+    "minContains",
+    "maxContains",
+];
+
+impl Node {
+    fn compile(schema: &Value, at: &Pointer) -> Result<Self, SchemaError> {
+        let keywords = match schema {
+            Value::Bool(b) => return Ok(Node::Bool(*b)),
+            Value::Object(keywords) => keywords,
+            _ => return Err(SchemaError::new(at, "expected an object or a boolean")),
+        };
+        if let Some(keyword) = UNSUPPORTED.iter().find(|k| keywords.contains_key(**k)) {
+            return Err(SchemaError::new(
+                &at.join(*keyword),
+                format!("{} is not supported", keyword),
+            ));
+        }
+        let schema_at = at;
+        let mut rules = Vec::new();
+        for (keyword, operand) in keywords {
+            let at = at.join(keyword.as_str());
+            let rule = match keyword.as_str() {
+                "type" => Rule::Type(types(operand, &at)?),
+                "enum" => match operand {
+                    Value::Array(values) => Rule::Enum(values.clone()),
+                    _ => return Err(SchemaError::new(&at, "expected an array")),
+                },
+                "const" => Rule::Const(operand.clone()),
+                "minimum" => Rule::Minimum(number(operand, &at)?),
+                "maximum" => Rule::Maximum(number(operand, &at)?),
+                "exclusiveMinimum" => Rule::ExclusiveMinimum(number(operand, &at)?),
+                "exclusiveMaximum" => Rule::ExclusiveMaximum(number(operand, &at)?),
+                "multipleOf" => match number(operand, &at)? {
+                    m if m > 0.0 => Rule::MultipleOf(m),
+                    _ => return Err(SchemaError::new(&at, "expected a number above 0")),
+                },
+                "minLength" => Rule::MinLength(count(operand, &at)?),
+                "maxLength" => Rule::MaxLength(count(operand, &at)?),
+                "pattern" => Rule::Pattern(pattern(operand, &at)?),
+                "minItems" => Rule::MinItems(count(operand, &at)?),
+                "maxItems" => Rule::MaxItems(count(operand, &at)?),
+                "uniqueItems" => match operand {
+                    Value::Bool(true) => Rule::UniqueItems,
+                    Value::Bool(false) => continue,
+                    _ => return Err(SchemaError::new(&at, "expected true or false")),
+                },
+                "prefixItems" => Rule::PrefixItems(schemas(operand, &at)?),
+                "items" => Rule::Items {
+                    skip: match keywords.get("prefixItems") {
+                        Some(Value::Array(prefix)) => prefix.len(),
+                        _ => 0,
+                    },
+                    schema: Box::new(Node::compile(operand, &at)?),
+                },
+                "contains" => Rule::Contains(Box::new(Node::compile(operand, &at)?)),
+                "minProperties" => Rule::MinProperties(count(operand, &at)?),
+                "maxProperties" => Rule::MaxProperties(count(operand, &at)?),
+                "required" => Rule::Required(names(operand, &at)?),
+                "dependentRequired" => Rule::DependentRequired(
+                    members(operand, &at)?
+                        .map(|(name, required, at)| Ok((name.clone(), names(required, &at)?)))
+                        .collect::<Result<_, SchemaError>>()?,
+                ),
+                "properties" => Rule::Properties(
+                    members(operand, &at)?
+                        .map(|(name, schema, at)| Ok((name.clone(), Node::compile(schema, &at)?)))
+                        .collect::<Result<_, SchemaError>>()?,
+                ),
+                "patternProperties" => Rule::PatternProperties(
+                    members(operand, &at)?
+                        .map(|(name, schema, at)| {
+                            let regex = Regex::new(name)
+                                .map_err(|e| SchemaError::new(&at, e.to_string()))?;
+                            Ok((regex, Node::compile(schema, &at)?))
+                        })
+                        .collect::<Result<_, SchemaError>>()?,
+                ),
+                "additionalProperties" => Rule::AdditionalProperties {
+                    known: match keywords.get("properties") {
+                        Some(Value::Object(properties)) => properties.keys().cloned().collect(),
+                        _ => Vec::new(),
+                    },
+                    patterns: match keywords.get("patternProperties") {
+                        Some(Value::Object(patterns)) => patterns
+                            .keys()
+                            .filter_map(|pattern| Regex::new(pattern).ok())
+                            .collect(),
+                        _ => Vec::new(),
+                    },
+                    schema: Box::new(Node::compile(operand, &at)?),
+                },
+                "propertyNames" => Rule::PropertyNames(Box::new(Node::compile(operand, &at)?)),
+                "allOf" => Rule::AllOf(schemas(operand, &at)?),
+                "anyOf" => Rule::AnyOf(schemas(operand, &at)?),
+                "oneOf" => Rule::OneOf(schemas(operand, &at)?),
+                "not" => Rule::Not(Box::new(Node::compile(operand, &at)?)),
+                "if" => {
+                    let branch = |keyword: &str| {
+                        keywords
+                            .get(keyword)
+                            .map(|schema| {
+                                Node::compile(schema, &schema_at.join(keyword)).map(Box::new)
+                            })
+                            .transpose()
+                    };
+                    Rule::If {
+                        condition: Box::new(Node::compile(operand, &at)?),
+                        then: branch("then")?,
+                        otherwise: branch("else")?,
+                    }
+                }
+                // Annotations, `then`/`else` (read with `if`) and unknown keywords, which
+                // the specification says to ignore.
+                _ => continue,
+            };
+            rules.push(rule);
+        }
+        Ok(Node::Rules(rules))
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        let mut violations = Vec::new();
+        self.check(value, &Pointer::root(), &mut violations);
+        violations.is_empty()
+    }
+
+    fn check(&self, value: &Value, path: &Pointer, out: &mut Vec<Violation>) {
+        let rules = match self {
+            Node::Bool(true) => return,
+            Node::Bool(false) => return violation(out, path, "no value is allowed here"),
+            Node::Rules(rules) => rules,
+        };
+        for rule in rules {
+            rule.check(value, path, out);
+        }
+    }
+}
+
+impl Rule {
+    fn check(&self, value: &Value, path: &Pointer, out: &mut Vec<Violation>) {
+        let number = value.as_f64();
+        let length = value.as_str().map(|s| s.chars().count());
+        let (array, object) = (value.as_array(), value.as_object());
+        match self {
+            Rule::Type(types) if !types.iter().any(|t| t.matches(value)) => {
+                let names: Vec<_> = types.iter().map(|t| t.name()).collect();
+                let found = type_name(value);
+                violation(
+                    out,
+                    path,
+                    format!("expected {}, found {}", names.join(" or "), found),
+                )
+            }
+            Rule::Enum(values) if !values.iter().any(|v| json_eq(v, value)) => {
+                violation(out, path, "must be one of the allowed values")
+            }
+            Rule::Const(constant) if !json_eq(constant, value) => {
+                violation(out, path, format!("must equal {}", constant))
+            }
+            Rule::Minimum(m) if number.is_some_and(|n| n < *m) => {
+                violation(out, path, format!("must be at least {}", m))
+            }
+            Rule::Maximum(m) if number.is_some_and(|n| n > *m) => {
+                violation(out, path, format!("must be at most {}", m))
+            }
+            Rule::ExclusiveMinimum(m) if number.is_some_and(|n| n <= *m) => {
+                violation(out, path, format!("must be greater than {}", m))
+            }
+            Rule::ExclusiveMaximum(m) if number.is_some_and(|n| n >= *m) => {
+                violation(out, path, format!("must be less than {}", m))
+            }
+            Rule::MultipleOf(m) if number.is_some_and(|n| !is_multiple(n, *m)) => {
+                violation(out, path, format!("must be a multiple of {}", m))
+            }
+            Rule::MinLength(n) if length.is_some_and(|l| l < *n) => {
+                violation(out, path, format!("must be at least {} characters long", n))
+            }
+            Rule::MaxLength(n) if length.is_some_and(|l| l > *n) => {
+                violation(out, path, format!("must be at most {} characters long", n))
+            }
+            Rule::Pattern(regex) if value.as_str().is_some_and(|s| !regex.is_match(s)) => {
+                violation(out, path, format!("must match the pattern {}", regex))
+            }
+            Rule::MinItems(n) if array.is_some_and(|a| a.len() < *n) => {
+                violation(out, path, format!("must have at least {} items", n))
+            }
+            Rule::MaxItems(n) if array.is_some_and(|a| a.len() > *n) => {
+                violation(out, path, format!("must have at most {} items", n))
+            }
+            Rule::UniqueItems if array.is_some_and(|items| has_duplicates(items)) => {
+                violation(out, path, "items must be unique")
+            }
+            Rule::PrefixItems(schemas) => {
+                let items = array.into_iter().flatten();
+                for (i, (schema, item)) in schemas.iter().zip(items).enumerate() {
+                    schema.check(item, &path.join(i.to_string()), out);
+                }
+            }
+            Rule::Items { skip, schema } => {
+                for (i, item) in array.into_iter().flatten().enumerate().skip(*skip) {
+                    schema.check(item, &path.join(i.to_string()), out);
+                }
+            }
+            Rule::Contains(schema)
+                if array.is_some_and(|items| !items.iter().any(|item| schema.matches(item))) =>
+            {
+                violation(out, path, "must contain a matching item")
+            }
+            Rule::MinProperties(n) if object.is_some_and(|o| o.len() < *n) => {
+                violation(out, path, format!("must have at least {} properties", n))
+            }
+            Rule::MaxProperties(n) if object.is_some_and(|o| o.len() > *n) => {
+                violation(out, path, format!("must have at most {} properties", n))
+            }
+            Rule::Required(names) => {
+                let Some(object) = object else {
+                    return;
+                };
+                for name in names.iter().filter(|name| !object.contains_key(*name)) {
+                    violation(out, &path.join(name.as_str()), "is required");
+                }
+            }
+            Rule::DependentRequired(dependencies) => {
+                let Some(object) = object else {
+                    return;
+                };
+                for (name, names) in dependencies {
+                    if !object.contains_key(name) {
+                        continue;
+                    }
+                    for required in names.iter().filter(|n| !object.contains_key(*n)) {
+                        violation(
+                            out,
+                            &path.join(required.as_str()),
+                            format!("is required when {} is present", name),
+                        );
+                    }
+                }
+            }
+            Rule::Properties(properties) => {
+                let Some(object) = object else {
+                    return;
+                };
+                for (name, schema) in properties {
+                    if let Some(member) = object.get(name) {
+                        schema.check(member, &path.join(name.as_str()), out);
+                    }
+                }
+            }
+            Rule::PatternProperties(patterns) => {
+                for (name, member) in object.into_iter().flatten() {
+                    for (regex, schema) in patterns {
+                        if regex.is_match(name) {
+                            schema.check(member, &path.join(name.as_str()), out);
+                        }
+                    }
+                }
+            }
+            Rule::AdditionalProperties {
+                known,
+                patterns,
+                schema,
+            } => {
+                for (name, member) in object.into_iter().flatten() {
+                    if known.contains(name) || patterns.iter().any(|r| r.is_match(name)) {
+                        continue;
+                    }
+                    let at = path.join(name.as_str());
+                    match schema.as_ref() {
+                        Node::Bool(false) => violation(out, &at, "is not allowed"),
+                        schema => schema.check(member, &at, out),
+                    }
+                }
+            }
+            Rule::PropertyNames(schema) => {
+                for name in object.into_iter().flat_map(Map::keys) {
+                    if !schema.matches(&Value::String(name.clone())) {
+                        violation(
+                            out,
+                            &path.join(name.as_str()),
+                            "is not an allowed property name",
+                        );
+                    }
+                }
+            }
+            Rule::AllOf(schemas) => {
+                for schema in schemas {
+                    schema.check(value, path, out);
+                }
+            }
+            Rule::AnyOf(schemas) if !schemas.iter().any(|schema| schema.matches(value)) => {
+                violation(out, path, "must match at least one schema in anyOf")
+            }
+            Rule::OneOf(schemas) => {
+                let matched = schemas
+                    .iter()
+                    .filter(|schema| schema.matches(value))
+                    .count();
+                if matched != 1 {
+                    violation(
+                        out,
+                        path,
+                        format!(
+                            "must match exactly one schema in oneOf, matched {}",
+                            matched
+                        ),
+                    );
+                }
+            }
+            Rule::Not(schema) if schema.matches(value) => {
+                violation(out, path, "must not match the schema in not")
+            }
+            Rule::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let branch = if condition.matches(value) {
+                    then
+                } else {
+                    otherwise
+                };
+                if let Some(schema) = branch {
+                    schema.check(value, path, out);
+                }
+            }
+            // The value meets the assertion, or is of a type it doesn't apply to.
+            _ => {}
+        }
+    }
+}
+
+fn is_multiple(n: f64, m: f64) -> bool {
+    let quotient = n / m;
+    (quotient - quotient.round()).abs() <= 1e-9
+}
+
+fn has_duplicates(items: &[Value]) -> bool {
+    items
+        .iter()
+        .enumerate()
+        .any(|(i, a)| items[i + 1..].iter().any(|b| json_eq(a, b)))
+}
+
+impl Type {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "null" => Type::Null,
+            "boolean" => Type::Boolean,
+            "integer" => Type::Integer,
+            "number" => Type::Number,
+            "string" => Type::String,
+            "array" => Type::Array,
+            "object" => Type::Object,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Type::Null => "null",
+            Type::Boolean => "boolean",
+            Type::Integer => "integer",
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Array => "array",
+            Type::Object => "object",
+        }
+    }
+
+    fn matches(self, value: &Value) -> bool {
+        match (self, value) {
+            (Type::Null, Value::Null)
+            | (Type::Boolean, Value::Bool(_))
+            | (Type::Number, Value::Number(_))
+            | (Type::String, Value::String(_))
+            | (Type::Array, Value::Array(_))
+            | (Type::Object, Value::Object(_)) => true,
+            // Whole floats such as 1.0 count as integers too.
+            (Type::Integer, Value::Number(n)) => {
+                n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|f| f.fract() == 0.0)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn violation(out: &mut Vec<Violation>, path: &Pointer, message: impl Into<String>) {
+    out.push(Violation {
+        path: path.clone(),
+        message: message.into(),
+    });
+}
+
+fn types(operand: &Value, at: &Pointer) -> Result<Vec<Type>, SchemaError> {
+    let parse = |name: &Value, at: &Pointer| {
+        name.as_str()
+            .and_then(Type::parse)
+            .ok_or_else(|| SchemaError::new(at, format!("unknown type {}", name)))
+    };
+    match operand {
+        Value::Array(names) => names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| parse(name, &at.join(i.to_string())))
+            .collect(),
+        name => Ok(vec![parse(name, at)?]),
+    }
+}
+
+fn number(operand: &Value, at: &Pointer) -> Result<f64, SchemaError> {
+    operand
+        .as_f64()
+        .ok_or_else(|| SchemaError::new(at, "expected a number"))
+}
+
+fn count(operand: &Value, at: &Pointer) -> Result<usize, SchemaError> {
+    operand
+        .as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| SchemaError::new(at, "expected a non-negative integer"))
+}
+
+fn pattern(operand: &Value, at: &Pointer) -> Result<Regex, SchemaError> {
+    match operand {
+        Value::String(pattern) => {
+            Regex::new(pattern).map_err(|e| SchemaError::new(at, e.to_string()))
+        }
+        _ => Err(SchemaError::new(at, "expected a string")),
+    }
+}
+
+fn names(operand: &Value, at: &Pointer) -> Result<Vec<String>, SchemaError> {
+    let Value::Array(names) = operand else {
+        return Err(SchemaError::new(at, "expected an array of strings"));
+    };
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| match name {
+            Value::String(name) => Ok(name.clone()),
+            _ => Err(SchemaError::new(
+                &at.join(i.to_string()),
+                "expected a string",
+            )),
+        })
+        .collect()
+}
+
+fn schemas(operand: &Value, at: &Pointer) -> Result<Vec<Node>, SchemaError> {
+    match operand {
+        Value::Array(schemas) if !schemas.is_empty() => schemas
+            .iter()
+            .enumerate()
+            .map(|(i, schema)| Node::compile(schema, &at.join(i.to_string())))
+            .collect(),
+        _ => Err(SchemaError::new(
+            at,
+            "expected a non-empty array of schemas",
+        )),
+    }
+}
+
+/// The members of an object operand, each with where it sits in the schema.
+fn members<'a>(
+    operand: &'a Value,
+    at: &'a Pointer,
+) -> Result<impl Iterator<Item = (&'a String, &'a Value, Pointer)>, SchemaError> {
+    match operand {
+        Value::Object(members) => Ok(members
+            .iter()
+            .map(|(name, value)| (name, value, at.join(name.as_str())))),
+        _ => Err(SchemaError::new(at, "expected an object")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// The paths of the violations, as written in errors.
+    fn check(schema: Value, value: Value) -> Vec<String> {
+        let schema = Schema::compile(schema).unwrap();
+        let mut paths: Vec<_> = schema
+            .validate(&value)
+            .iter()
+            .map(|v| v.path.to_string())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn compile_error(schema: Value) -> String {
+        Schema::compile(schema).unwrap_err().at.to_string()
+    }
+
+    #[test]
+    fn unusable_schemas_are_rejected_where_they_fail() {
+        assert_eq!(compile_error(json!([])), "\"\"");
+        assert_eq!(compile_error(json!({"$ref": "#/a"})), "/$ref");
+        assert_eq!(
+            compile_error(json!({"properties": {"a": {"minContains": 1}}})),
+            "/properties/a/minContains"
+        );
+        assert_eq!(compile_error(json!({"type": "date"})), "/type");
+        assert_eq!(compile_error(json!({"multipleOf": 0})), "/multipleOf");
+        assert_eq!(compile_error(json!({"pattern": "("})), "/pattern");
+        assert_eq!(compile_error(json!({"minLength": -1})), "/minLength");
+        assert_eq!(compile_error(json!({"allOf": [true, 1]})), "/allOf/1");
+        assert!(Schema::compile(json!({"title": "t", "x-custom": 1})).is_ok());
+    }
+
+    #[test]
+    fn types_and_values() {
+        let integer = json!({"type": "integer"});
+        assert!(check(integer.clone(), json!(1.0)).is_empty());
+        assert_eq!(check(integer, json!(1.5)), ["\"\""]);
+        assert!(check(json!({"type": ["string", "null"]}), json!(null)).is_empty());
+        assert_eq!(check(json!({"type": "number"}), json!("1")), ["\"\""]);
+        assert!(check(json!({"enum": [1, "a"]}), json!(1.0)).is_empty());
+        assert_eq!(check(json!({"const": {"a": 1}}), json!({"a": 2})), ["\"\""]);
+        assert!(check(json!(true), json!(1)).is_empty());
+        assert_eq!(check(json!(false), json!(1)), ["\"\""]);
+    }
+
+    #[test]
+    fn numbers_and_strings() {
+        let range = json!({"minimum": 1, "exclusiveMaximum": 3, "multipleOf": 0.5});
+        assert!(check(range.clone(), json!(2.5)).is_empty());
+        assert_eq!(check(range.clone(), json!(3)).len(), 1);
+        assert_eq!(check(range.clone(), json!(0.7)).len(), 2);
+        assert!(check(range, json!("not a number")).is_empty());
+        let string = json!({"minLength": 2, "maxLength": 3, "pattern": "^a"});
+        assert!(check(string.clone(), json!("ab")).is_empty());
+        assert_eq!(check(string.clone(), json!("é")).len(), 2);
+        assert_eq!(check(string, json!("abcd")).len(), 1);
+    }
+
+    #[test]
+    fn arrays() {
+        let schema = json!({
+            "prefixItems": [{"type": "string"}],
+            "items": {"type": "integer"},
+            "minItems": 2,
+            "uniqueItems": true,
+            "contains": {"const": 2}
+        });
+        assert!(check(schema.clone(), json!(["a", 2])).is_empty());
+        assert_eq!(check(schema.clone(), json!([1, "b"])), ["\"\"", "/0", "/1"]);
+        assert_eq!(check(schema, json!(["a", 2, 2.0])), ["\"\""]);
+    }
+
+    #[test]
+    fn objects() {
+        let schema = json!({
+            "properties": {"a": {"type": "integer"}},
+            "patternProperties": {"^x-": {"type": "string"}},
+            "additionalProperties": false,
+            "required": ["a"],
+            "dependentRequired": {"x-b": ["x-c"]},
+            "propertyNames": {"maxLength": 3}
+        });
+        assert!(check(schema.clone(), json!({"a": 1, "x-c": "c"})).is_empty());
+        assert_eq!(
+            check(schema.clone(), json!({"x-b": 1, "long": 1})),
+            ["/a", "/long", "/long", "/x-b", "/x-c"]
+        );
+        assert!(check(schema, json!([1])).is_empty());
+    }
+
+    #[test]
+    fn combinators_and_conditions() {
+        let one_of = json!({"oneOf": [{"type": "integer"}, {"minimum": 0}]});
+        assert!(check(one_of.clone(), json!(-1)).is_empty());
+        assert_eq!(check(one_of, json!(1)).len(), 1);
+        assert_eq!(
+            check(json!({"anyOf": [{"type": "null"}]}), json!(1)).len(),
+            1
+        );
+        assert_eq!(
+            check(json!({"not": {"type": "string"}}), json!("a")).len(),
+            1
+        );
+        let conditional = json!({
+            "if": {"properties": {"kind": {"const": "a"}}},
+            "then": {"required": ["a"]},
+            "else": {"required": ["b"]}
+        });
+        assert_eq!(check(conditional.clone(), json!({"kind": "a"})), ["/a"]);
+        assert_eq!(check(conditional, json!({"kind": "c"})), ["/b"]);
+    }
+}
@@ -0,0 +1,223 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use common::message::{decode, encode, DeserializeError, SerializeError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::aof::AofError;
+use crate::index::IndexSpec;
+use crate::schema::SchemaError;
+
+/// Marks a file as a rayo snapshot.
+const MAGIC: &[u8; 8] = b"RAYOSNAP";
+/// Bumped whenever the payload layout changes incompatibly.
+const FORMAT_VERSION: u16 = 1;
+/// Magic, format version, payload checksum and payload length.
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4 + 8;
+
+/// A point-in-time copy of the keyspace.
+///
+/// On disk it is a fixed header followed by the snapshot encoded the same way as messages
+/// on the wire (msgpack, then zstd). The header carries a CRC32 of the payload so a
+/// damaged file is rejected instead of half-read.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub collections: Vec<CollectionDump>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CollectionDump {
+    pub name: String,
+    pub objects: Vec<ObjectDump>,
+    #[serde(default)]
+    pub indexes: Vec<IndexSpec>,
+    #[serde(default)]
+    pub schema: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ObjectDump {
+    pub id: String,
+    pub value: Value,
+    /// Unix time in milliseconds at which the object expires.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Version of the object when it was captured; kept by log rewrites but not by LOAD.
+    #[serde(default)]
+    pub version: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0}")]
+    Encode(#[from] SerializeError),
+
+    #[error("corrupt snapshot: {0}")]
+    Decode(#[from] DeserializeError),
+
+    #[error("not a snapshot file")]
+    BadMagic,
+
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u16),
+
+    #[error("snapshot is truncated")]
+    Truncated,
+
+    #[error("snapshot checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("append-only log write failed: {0}")]
+    Log(#[from] AofError),
+
+    #[error("{0}")]
+    Schema(#[from] SchemaError),
+}
+
+impl Snapshot {
+    /// Writes the snapshot to `path`, replacing any previous file only once the new one is
+    /// complete and synced.
+    pub fn write(&self, path: &Path) -> Result<(), SnapshotError> {
+        let payload = encode(self)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        file.write_all(&(payload.len() as u64).to_le_bytes())?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        // The rename only lasts through a crash once the directory is synced too.
+        if let Some(dir) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Reads and fully verifies a snapshot written by [`Snapshot::write`].
+    pub fn read(path: &Path) -> Result<Self, SnapshotError> {
+        let bytes = fs::read(path)?;
+        if bytes.len() < HEADER_LEN {
+            return Err(
+                if bytes.starts_with(&MAGIC[..bytes.len().min(MAGIC.len())]) {
+                    SnapshotError::Truncated
+                } else {
+                    SnapshotError::BadMagic
+                },
+            );
+        }
+        let (header, payload) = bytes.split_at(HEADER_LEN);
+        if &header[..8] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let checksum = u32::from_le_bytes(header[10..14].try_into().unwrap());
+        let len = u64::from_le_bytes(header[14..22].try_into().unwrap());
+        if (payload.len() as u64) < len {
+            return Err(SnapshotError::Truncated);
+        }
+        let payload = &payload[..len as usize];
+        if crc32fast::hash(payload) != checksum {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+        Ok(decode(payload)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rayo-snapshot-{}-{}", std::process::id(), name))
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            collections: vec![CollectionDump {
+                name: "users".to_string(),
+                objects: vec![ObjectDump {
+                    id: "a".to_string(),
+                    value: json!({"name": "a", "tags": [1, 2.5, null]}),
+                    expires_at: Some(42),
+                    version: 3,
+                }],
+                indexes: Vec::new(),
+                schema: Some(json!({"type": "object"})),
+            }],
+        }
+    }
+
+    /// Writes a snapshot, lets `damage` change the file and reads it back.
+    fn read_back(name: &str, damage: impl FnOnce(&mut Vec<u8>)) -> Result<Snapshot, SnapshotError> {
+        let path = temp_path(name);
+        snapshot().write(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        damage(&mut bytes);
+        fs::write(&path, &bytes).unwrap();
+        let read = Snapshot::read(&path);
+        let _ = fs::remove_file(&path);
+        read
+    }
+
+    #[test]
+    fn round_trips() {
+        let read = read_back("round-trip", |_| {}).unwrap();
+        assert_eq!(read.collections.len(), 1);
+        let collection = &read.collections[0];
+        assert_eq!(collection.name, "users");
+        assert_eq!(collection.schema, Some(json!({"type": "object"})));
+        let object = &collection.objects[0];
+        assert_eq!(object.id, "a");
+        assert_eq!(object.value, json!({"name": "a", "tags": [1, 2.5, null]}));
+        assert_eq!((object.expires_at, object.version), (Some(42), 3));
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let read = read_back("magic", |bytes| bytes[0] = b'X');
+        assert!(matches!(read, Err(SnapshotError::BadMagic)));
+        let read = read_back("version", |bytes| bytes[8] = 9);
+        assert!(matches!(read, Err(SnapshotError::UnsupportedVersion(9))));
+        let read = read_back("checksum", |bytes| *bytes.last_mut().unwrap() ^= 0xff);
+        assert!(matches!(read, Err(SnapshotError::ChecksumMismatch)));
+        let read = read_back("truncated", |bytes| bytes.truncate(bytes.len() - 1));
+        assert!(matches!(read, Err(SnapshotError::Truncated)));
+        let read = read_back("header", |bytes| bytes.truncate(HEADER_LEN - 1));
+        assert!(matches!(read, Err(SnapshotError::Truncated)));
+        let read = read_back("empty", Vec::clear);
+        assert!(matches!(read, Err(SnapshotError::Truncated)));
+    }
+
+    #[test]
+    fn a_missing_file_is_an_io_error() {
+        let read = Snapshot::read(&temp_path("missing"));
+        assert!(matches!(read, Err(SnapshotError::Io(e)) if e.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn writes_replace_the_previous_file() {
+        let path = temp_path("replace");
+        fs::write(&path, b"old").unwrap();
+        snapshot().write(&path).unwrap();
+        let read = Snapshot::read(&path);
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        assert!(!Path::new(&tmp).exists());
+        let _ = fs::remove_file(&path);
+        assert_eq!(read.unwrap().collections[0].objects.len(), 1);
+    }
+}
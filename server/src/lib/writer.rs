@@ -1,10 +1,10 @@
 use bytes::Bytes;
-use common::message::{Command, Response};
+use common::message::Response;
 use futures::{stream::SplitSink, SinkExt};
 use thiserror::Error;
 use tokio::{net::TcpStream, sync::mpsc::Receiver};
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use tracing::{error, info};
+use tracing::error;
 
 #[derive(Debug, Error)]
 pub enum WriterError {